@@ -1,17 +1,40 @@
-use crate::cpu::instr::Encoding;
+use crate::cpu::instr::{Encoding, Flow, control_flow};
 use crate::cpu::sreg::SregCoreState;
 use crate::devices::bus::{Bus, Device};
+use crate::debug::symbols::SymbolTable;
+use crate::support::trace;
 use super::instr::execute;
 
+use std::rc::Rc;
+use std::cell::RefCell;
+
 pub struct State {
     pub reg: [u16; 8],
     pub pc: u16,
     pub flags: u16,
 }
 
+/// Information about retired instruction passed to execution hooks
+pub struct ExecEvent {
+    pub pc: u16,
+    pub phys_addr: u32, // bus address instruction was fetched from
+    pub instr: u32,
+    pub next_pc: u16, // pc after instruction, before entering interrupt handler
+    pub flow: Flow,
+    pub privileged: bool,
+    pub interrupted: bool, // interrupt was taken after this instruction
+}
+
+pub trait ExecHook {
+    fn executed(&mut self, ev: &ExecEvent);
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
     pub state: State,
     pub sregs: SregCoreState,
+    pub symbols: Rc<SymbolTable>,
+    hooks: Vec<Rc<RefCell<dyn ExecHook>>>,
 
     bus: Bus // TODO: remove mut and make bus a pointer??
 }
@@ -20,8 +43,7 @@ impl CPU {
     fn data_wb_addr(&self, cpu_addr: u16, word: bool) -> (u32, u8) {
         let wb_adr = self.sregs.dmmu_translate(cpu_addr>>1);
         let sel = if word { 0b11 } else { 0b01 << (cpu_addr&1) };
-        return (wb_adr, sel);
-
+        (wb_adr, sel)
     }
 
     pub fn read(&mut self, cpu_addr: u16, word: bool) -> u16 {
@@ -43,7 +65,7 @@ impl CPU {
     pub fn fetch(&mut self) -> u32 {
         // in ppcpu, icache requests lines from wb 16 bit addresses, that are translated later
         let base_addr = self.sregs.immu_translate(self.state.pc<<1);
-        println!("immu {:#06x} -> {:#08x} (8a{:#08x})", self.state.pc<<1, base_addr, base_addr<<1);
+        crate::trace!("immu {:#06x} <{}> -> {:#08x} (8a{:#08x})", self.state.pc<<1, self.symbols.format(self.state.pc as u32), base_addr, base_addr<<1);
         let low_part = self.bus.read(base_addr, 0b11) as u32;
         let high_part = self.bus.read(base_addr+1, 0b11) as u32;
 
@@ -51,11 +73,13 @@ impl CPU {
     }
 
     pub fn execute(&mut self, instr: u32) {
-        println!("{:#010x}", instr);
-        for i in 0..8 {
-            print!("r{}: {:#06x} ", i, self.state.reg[i]);
+        if trace::enabled() {
+            println!("{:#010x}", instr);
+            for i in 0..8 {
+                print!("r{}: {:#06x} ", i, self.state.reg[i]);
+            }
+            println!();
         }
-        println!("");
         let encoding = Encoding::from_raw(instr);
         execute(&encoding, self);
     }
@@ -64,14 +88,49 @@ impl CPU {
 
 impl CPU {
     pub fn tick(&mut self) {
+        let pc = self.state.pc;
+        let privileged = self.sregs.is_privileged();
+        let phys_addr = self.sregs.immu_translate(pc<<1); // before execution may switch paging
+
         let insn = self.fetch();
         self.execute(insn);
+        let next_pc = self.state.pc;
+        self.bus.tick();
+
+        let interrupted = self.sregs.interrupt(&mut self.state);
+
+        if !self.hooks.is_empty() {
+            let ev = ExecEvent {
+                pc, phys_addr, instr: insn, next_pc, privileged, interrupted,
+                flow: control_flow(&Encoding::from_raw(insn)),
+            };
+            for hook in &self.hooks {
+                hook.borrow_mut().executed(&ev);
+            }
+        }
+    }
+
+    /// Registers and state summary, printed when run stops
+    pub fn dump_state(&self) -> String {
+        let regs: Vec<_> = self.state.reg.iter().enumerate().map(|(i, r)| format!("r{}: {:#06x}", i, r)).collect();
+        format!("{}\npc: {:#06x} <{}> flags: {:#06x} priv: {} irq: {}", regs.join(" "),
+                self.state.pc, self.symbols.format(self.state.pc as u32), self.state.flags,
+                self.sregs.is_privileged() as u8, self.sregs.irq_enabled() as u8)
+    }
+
+    /// System reset, execution restarts at pc 0 (boot ROM) with all devices reset
+    pub fn reset(&mut self) {
+        self.state = State::new();
+        self.sregs.reset();
+        self.bus.reset();
+    }
 
-        self.sregs.interrupt(&mut self.state)
+    pub fn add_hook(&mut self, hook: Rc<RefCell<dyn ExecHook>>) {
+        self.hooks.push(hook);
     }
 
-    pub fn new(bus: Bus, coreid: u16) -> CPU {
-       CPU {state: State::new(), sregs: SregCoreState::new(coreid), bus: bus} 
+    pub fn new(bus: Bus, coreid: u16, symbols: Rc<SymbolTable>) -> CPU {
+       CPU {state: State::new(), sregs: SregCoreState::new(coreid), symbols, hooks: vec![], bus} 
     }
 }
 
@@ -1,4 +1,8 @@
+// operation table spells out register updates as `x = x op y`, like the hardware description
+#![allow(clippy::assign_op_pattern)]
+
 use crate::cpu::cpu::CPU;
+use crate::debug::symbols::SymbolTable;
 use bitflags::bitflags;
 
 use std::collections::HashMap;
@@ -17,6 +21,7 @@ bitflags! {
 
 #[derive(Debug)]
 pub struct Encoding {
+    raw : u32,
     opcode : u8,
     rd : u8,
     rs1 : u8,
@@ -30,7 +35,8 @@ fn extract(field: u32, start: usize, len: usize) -> u32 {
 
 impl Encoding {
     pub fn from_raw(instr: u32) -> Self {
-        Self {opcode: extract(instr, 0,  6) as u8,
+        Self {raw: instr,
+              opcode: extract(instr, 0,  6) as u8,
               rd:     extract(instr, 7,  3) as u8,
               rs1:    extract(instr, 10, 3) as u8,
               rs2:    extract(instr, 13, 3) as u8,
@@ -39,6 +45,7 @@ impl Encoding {
     }
 }
 
+#[allow(clippy::upper_case_acronyms)] // ISA mnemonics
 #[derive(PartialEq, Eq, Hash, Debug)]
 #[repr(u8)]
 enum Opcode {
@@ -87,7 +94,7 @@ enum Opcode {
 
 struct Operation {
     execute: fn(&Encoding, &mut CPU),
-    repr: fn(&Encoding) -> String,
+    repr: fn(&Encoding, &SymbolTable) -> String,
 }
 
 
@@ -100,7 +107,7 @@ lazy_static! {
             execute: |_enc, cpu| {
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |_enc| String::from("nop"),
+            repr: |_enc, _syms| String::from("nop"),
         });
         m.insert(Opcode::MOV as u8, Operation {
             execute: |enc, cpu| {
@@ -108,7 +115,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("mov r{}, r{}", enc.rd, enc.rs1),
+            repr: |enc, _syms| format!("mov r{}, r{}", enc.rd, enc.rs1),
         });
         m.insert(Opcode::LDD as u8, Operation {
             execute: |enc, cpu| {
@@ -116,7 +123,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("ldd r{0}, {1}", enc.rd, enc.imm),
+            repr: |enc, _syms| format!("ldd r{0}, {1}", enc.rd, enc.imm),
         });
         m.insert(Opcode::LDO as u8, Operation {
             execute: |enc, cpu| {
@@ -124,7 +131,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("ldo r{}, r{}, {}", enc.rd, enc.rs1, enc.imm),
+            repr: |enc, _syms| format!("ldo r{}, r{}, {}", enc.rd, enc.rs1, enc.imm),
         });
         m.insert(Opcode::LDI as u8, Operation {
             execute: |enc, cpu| {
@@ -132,7 +139,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("ldi r{}, {}", enc.rd, enc.imm),
+            repr: |enc, _syms| format!("ldi r{}, {}", enc.rd, enc.imm),
         });
         m.insert(Opcode::STD as u8, Operation {
             execute: |enc, cpu| {
@@ -140,7 +147,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("std r{}, {}", enc.rs1, enc.imm),
+            repr: |enc, _syms| format!("std r{}, {}", enc.rs1, enc.imm),
         });
         m.insert(Opcode::STO as u8, Operation {
             execute: |enc, cpu| {
@@ -148,7 +155,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("sto r{}, r{}, {}", enc.rs1, enc.rs2, enc.imm),
+            repr: |enc, _syms| format!("sto r{}, r{}, {}", enc.rs1, enc.rs2, enc.imm),
         });
         m.insert(Opcode::ADD as u8, Operation {
             execute: |enc, cpu|{
@@ -158,7 +165,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("add r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
+            repr: |enc, _syms| format!("add r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
         });
         m.insert(Opcode::ADI as u8, Operation {
             execute: |enc, cpu| {
@@ -168,7 +175,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("adi r{0}, r{1}, {2}", enc.rd, enc.rs1, enc.imm),
+            repr: |enc, _syms| format!("adi r{0}, r{1}, {2}", enc.rd, enc.rs1, enc.imm),
         });
         m.insert(Opcode::ADC as u8, Operation {
             execute: |enc, cpu|{
@@ -178,7 +185,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("adc r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
+            repr: |enc, _syms| format!("adc r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
         });
         m.insert(Opcode::SUB as u8, Operation {
             execute: |enc, cpu| {
@@ -188,7 +195,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("sub r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
+            repr: |enc, _syms| format!("sub r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
         });
         m.insert(Opcode::SUC as u8, Operation {
             execute: |enc, cpu|{
@@ -198,7 +205,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("suc r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
+            repr: |enc, _syms| format!("suc r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
         });
         m.insert(Opcode::AND as u8, Operation {
             execute: |enc, cpu| {
@@ -208,7 +215,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("and r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
+            repr: |enc, _syms| format!("and r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
         });
         m.insert(Opcode::ORR as u8, Operation {
         execute: |enc, cpu| {
@@ -218,7 +225,7 @@ lazy_static! {
 
             cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("orr r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
+            repr: |enc, _syms| format!("orr r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
         });
         m.insert(Opcode::XOR as u8, Operation {
             execute: |enc, cpu| {
@@ -228,7 +235,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("xor r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
+            repr: |enc, _syms| format!("xor r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
         });
         m.insert(Opcode::ANI as u8, Operation {
             execute: |enc, cpu| {
@@ -238,7 +245,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("ani r{0}, r{1}, {2}", enc.rd, enc.rs1, enc.imm),
+            repr: |enc, _syms| format!("ani r{0}, r{1}, {2}", enc.rd, enc.rs1, enc.imm),
         });
         m.insert(Opcode::ORI as u8, Operation {
         execute: |enc, cpu| {
@@ -248,7 +255,7 @@ lazy_static! {
 
             cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("ori r{0}, r{1}, {2}", enc.rd, enc.rs1, enc.imm),
+            repr: |enc, _syms| format!("ori r{0}, r{1}, {2}", enc.rd, enc.rs1, enc.imm),
         });
         m.insert(Opcode::XOI as u8, Operation {
         execute: |enc, cpu| {
@@ -258,7 +265,7 @@ lazy_static! {
 
             cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("xoi r{0}, r{1}, {2}", enc.rd, enc.rs1, enc.imm),
+            repr: |enc, _syms| format!("xoi r{0}, r{1}, {2}", enc.rd, enc.rs1, enc.imm),
         });
         m.insert(Opcode::LD8 as u8, Operation {
             execute: |enc, cpu| {
@@ -266,7 +273,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("ld8 r{0}, {1}", enc.rd, enc.imm),
+            repr: |enc, _syms| format!("ld8 r{0}, {1}", enc.rd, enc.imm),
         });
         m.insert(Opcode::LO8 as u8, Operation {
             execute: |enc, cpu| {
@@ -274,7 +281,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("lo8 r{}, r{}, {}", enc.rd, enc.rs1, enc.imm),
+            repr: |enc, _syms| format!("lo8 r{}, r{}, {}", enc.rd, enc.rs1, enc.imm),
         });
         m.insert(Opcode::SD8 as u8, Operation {
             execute: |enc, cpu| {
@@ -282,7 +289,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("sd8 r{}, {}", enc.rs1, enc.imm),
+            repr: |enc, _syms| format!("sd8 r{}, {}", enc.rs1, enc.imm),
         });
         m.insert(Opcode::SO8 as u8, Operation {
             execute: |enc, cpu| {
@@ -290,7 +297,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("so8 r{}, r{}, {}", enc.rs1, enc.rs2, enc.imm),
+            repr: |enc, _syms| format!("so8 r{}, r{}, {}", enc.rs1, enc.rs2, enc.imm),
         });
         m.insert(Opcode::SHL as u8, Operation {
             execute: |enc, cpu| {
@@ -300,7 +307,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("shl r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
+            repr: |enc, _syms| format!("shl r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
         });
         m.insert(Opcode::SHR as u8, Operation {
             execute: |enc, cpu| {
@@ -310,7 +317,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("shr r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
+            repr: |enc, _syms| format!("shr r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
         });
         m.insert(Opcode::SLI as u8, Operation {
             execute: |enc, cpu| {
@@ -320,7 +327,7 @@ lazy_static! {
 
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("sli r{0}, r{1}, {2}", enc.rd, enc.rs1, enc.imm),
+            repr: |enc, _syms| format!("sli r{0}, r{1}, {2}", enc.rd, enc.rs1, enc.imm),
         });
         m.insert(Opcode::SRI as u8, Operation {
             execute: |enc, cpu| {
@@ -329,28 +336,28 @@ lazy_static! {
                 cpu.state.flags = gen_flag(false, cpu.state.reg[enc.rs1 as usize] as u32, enc.imm as u32, _out);
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("sri r{0}, r{1}, {2}", enc.rd, enc.rs1, enc.imm),
+            repr: |enc, _syms| format!("sri r{0}, r{1}, {2}", enc.rd, enc.rs1, enc.imm),
         });
         m.insert(Opcode::DIV as u8, Operation {
            execute: |enc, cpu| {
                cpu.state.reg[enc.rd as usize] = cpu.state.reg[enc.rs1 as usize] / cpu.state.reg[enc.rs2 as usize];
                cpu.state.pc = cpu.state.pc+1;
            },
-           repr: |enc| format!("div r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
+           repr: |enc, _syms| format!("div r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
         });
         m.insert(Opcode::MUL as u8, Operation {
             execute: |enc, cpu| {
                 cpu.state.reg[enc.rd as usize] = cpu.state.reg[enc.rs1 as usize] * cpu.state.reg[enc.rs2 as usize];
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("mul r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
+            repr: |enc, _syms| format!("mul r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
         });
         m.insert(Opcode::MOD as u8, Operation {
             execute: |enc, cpu| {
                 cpu.state.reg[enc.rd as usize] = cpu.state.reg[enc.rs1 as usize] % cpu.state.reg[enc.rs2 as usize];
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("mod r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
+            repr: |enc, _syms| format!("mod r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
         });
         m.insert(Opcode::CMP as u8, Operation {
             execute: |enc, cpu| {
@@ -358,7 +365,7 @@ lazy_static! {
                 cpu.state.flags = gen_flag(true, cpu.state.reg[enc.rs1 as usize] as u32, cpu.state.reg[enc.rs2 as usize] as u32, _out);
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("cmp r{}, r{}", enc.rs1, enc.rs2),
+            repr: |enc, _syms| format!("cmp r{}, r{}", enc.rs1, enc.rs2),
         });
         m.insert(Opcode::CMI as u8, Operation {
             execute: |enc, cpu| {
@@ -366,7 +373,7 @@ lazy_static! {
                 cpu.state.flags = gen_flag(true, cpu.state.reg[enc.rs1 as usize] as u32, enc.imm as u32, _out);
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("cmp r{}, {}", enc.rs1, enc.imm),
+            repr: |enc, _syms| format!("cmp r{}, {}", enc.rs1, enc.imm),
         });
         m.insert(Opcode::JAL as u8, Operation {
             execute: |enc, cpu| {
@@ -374,11 +381,11 @@ lazy_static! {
                 cpu.state.pc = enc.imm;
                 cpu.sregs.jtr_trig();
             },
-            repr: |enc| format!("jal r{}, {}",enc.rd, enc.imm),
+            repr: |enc, syms| format!("jal r{}, {}", enc.rd, syms.format(enc.imm as u32)),
         });
         m.insert(Opcode::JMP as u8, Operation {
             execute: |enc, cpu| {
-                let jmp_code: u8 = ((enc.rs1 << 3) as u8) +  enc.rd as u8;
+                let jmp_code: u8 = (enc.rs1 << 3) + enc.rd;
                 let cpu_flags: Flags = Flags::from_bits_truncate(cpu.state.flags);
                 let jump_condition_met: bool = match jmp_code {
                     0x0 => true,
@@ -405,34 +412,32 @@ lazy_static! {
                 }
 
             },
-            repr: |enc| {
-                let jmp_code: u8 = ((enc.rs1 << 3) as u8) +  enc.rd as u8;
-                let jmp_code_str: &str;
-
-                match jmp_code {
-                    0x0 => jmp_code_str = "jmp",
-                    0x1 => jmp_code_str = "jca",
-                    0x2 => jmp_code_str = "jeq",
-                    0x3 => jmp_code_str = "jlt",
-                    0x4 => jmp_code_str = "jgt",
-                    0x5 => jmp_code_str = "jle",
-                    0x6 => jmp_code_str = "jge",
-                    0x7 => jmp_code_str = "jne",
-                    0x8 => jmp_code_str = "jovf",
-                    0x9 => jmp_code_str = "jpar",
-                    0xA => jmp_code_str = "jgtu",
-                    0xB => jmp_code_str = "jgeu",
-                    0xC => jmp_code_str = "jleu",
-                    _ => jmp_code_str = "jump_code error",
-                }
-                format!("{} {}",jmp_code_str, enc.imm) },
+            repr: |enc, syms| {
+                let jmp_code: u8 = (enc.rs1 << 3) + enc.rd;
+                let jmp_code_str = match jmp_code {
+                    0x0 => "jmp",
+                    0x1 => "jca",
+                    0x2 => "jeq",
+                    0x3 => "jlt",
+                    0x4 => "jgt",
+                    0x5 => "jle",
+                    0x6 => "jge",
+                    0x7 => "jne",
+                    0x8 => "jovf",
+                    0x9 => "jpar",
+                    0xA => "jgtu",
+                    0xB => "jgeu",
+                    0xC => "jleu",
+                    _ => "jump_code error",
+                };
+                format!("{} {}", jmp_code_str, syms.format(enc.imm as u32)) },
         });
         m.insert(Opcode::SRL as u8, Operation {
             execute: |enc, cpu| {
                 cpu.state.reg[enc.rd as usize] = cpu.sregs.read(enc.imm, &cpu.state);
                 cpu.state.pc = cpu.state.pc + 1;
             },
-            repr: |enc| format!("srl r{}, {}", enc.rs1, enc.imm),
+            repr: |enc, _syms| format!("srl r{}, {}", enc.rs1, enc.imm),
         });
         m.insert(Opcode::SRS as u8, Operation {
             execute: |enc, cpu| {
@@ -441,7 +446,7 @@ lazy_static! {
                     cpu.state.pc = cpu.state.pc + 1;
                 }
             },
-            repr: |enc| format!("srs r{}, {}", enc.rs1, enc.imm),
+            repr: |enc, _syms| format!("srs r{}, {}", enc.rs1, enc.imm),
         });
         m.insert(Opcode::CAI as u8, Operation {
             execute: |enc, cpu| {
@@ -449,7 +454,7 @@ lazy_static! {
                 cpu.state.flags = gen_flag(true, cpu.state.reg[enc.rs1 as usize] as u32, enc.imm as u32, _out);
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("cai r{}, {}", enc.rs1, enc.imm),
+            repr: |enc, _syms| format!("cai r{}, {}", enc.rs1, enc.imm),
         });
         m.insert(Opcode::SAR as u8, Operation {
             execute: |enc, cpu| {
@@ -462,7 +467,7 @@ lazy_static! {
                 cpu.state.flags = gen_flag(false, cpu.state.reg[enc.rs1 as usize] as u32, cpu.state.reg[enc.rs2 as usize] as u32, _out);
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("sar r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
+            repr: |enc, _syms| format!("sar r{0}, r{1}, r{2}", enc.rd, enc.rs1, enc.rs2),
          });
          m.insert(Opcode::SAI as u8, Operation {
             execute: |enc, cpu| {
@@ -475,7 +480,7 @@ lazy_static! {
                 cpu.state.flags = gen_flag(false, cpu.state.reg[enc.rs1 as usize] as u32, enc.imm as u32, _out);
                 cpu.state.pc = cpu.state.pc+1;
             },
-            repr: |enc| format!("sai r{0}, r{1}, {2}", enc.rd, enc.rs1, enc.imm),
+            repr: |enc, _syms| format!("sai r{0}, r{1}, {2}", enc.rd, enc.rs1, enc.imm),
          });
         m.insert(Opcode::SEX as u8, Operation {
             execute: |enc, cpu|{
@@ -486,7 +491,7 @@ lazy_static! {
                 };
                 cpu.state.pc = cpu.state.pc + 1;
             },
-            repr: |enc| format!("sex r{}", enc.rs1)
+            repr: |enc, _syms| format!("sex r{}", enc.rs1)
         });
         m.insert(Opcode::SYS as u8, Operation {
             execute: |_enc, cpu| {
@@ -494,13 +499,13 @@ lazy_static! {
                cpu.state.pc = cpu.state.pc + 1; // pc must be incremetned to trigger interrupt
                                                 // "before" next instruction
             },
-            repr: |_enc| {String::from("sys")}
+            repr: |_enc, _syms| {String::from("sys")}
         });
         m.insert(Opcode::IRT as u8, Operation {
             execute: |_enc, cpu| {
                 cpu.state.pc = cpu.sregs.irt();
             },
-            repr: |_enc| {String::from("irt")}
+            repr: |_enc, _syms| {String::from("irt")}
         });
         m
     };
@@ -513,11 +518,38 @@ pub fn execute(enc: &Encoding, cpu: &mut CPU) {
             OP_MAP.get(&(Opcode::NOP as u8)).unwrap()
     });
 
-    print!("{}: ", cpu.state.pc);
-    println!("{}", ((op.repr)(enc)));
+    crate::trace!("{}: {}", cpu.symbols.format(cpu.state.pc as u32), ((op.repr)(enc, &cpu.symbols)));
     (op.execute)(enc, cpu);
 }
 
+/// Control flow class of instruction, used by profiling to track call stack
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Flow {
+    Sequential,
+    Jump,
+    Call,
+    Return,
+    InterruptReturn,
+}
+
+pub fn control_flow(enc: &Encoding) -> Flow {
+    match enc.opcode {
+        op if op == Opcode::JMP as u8 => Flow::Jump,
+        op if op == Opcode::JAL as u8 => Flow::Call,
+        // there are no dedicated return instruction, jumps to register are done by writing pc sreg
+        op if op == Opcode::SRS as u8 && enc.imm == crate::cpu::sreg::SREG::PC as u16 => Flow::Return,
+        op if op == Opcode::IRT as u8 => Flow::InterruptReturn,
+        _ => Flow::Sequential,
+    }
+}
+
+pub fn disassemble(enc: &Encoding, symbols: &SymbolTable) -> String {
+    match OP_MAP.get(&enc.opcode) {
+        Some(op) => (op.repr)(enc, symbols),
+        None => format!(".word {:#06x}, {:#06x}", enc.raw as u16, (enc.raw >> 16) as u16),
+    }
+}
+
 fn gen_flag(is_subtract: bool, var_1: u32, var_2: u32, var_out: u32) -> u16 {
     let mut temp_flag = Flags::empty();
     if extract(var_out, 15, 1) == 1 { temp_flag |= Flags::N; }
@@ -529,7 +561,7 @@ fn gen_flag(is_subtract: bool, var_1: u32, var_2: u32, var_out: u32) -> u16 {
 
     if var_out as u16 == 0 { temp_flag |= Flags::Z; }
 
-    if var_out.count_ones() % 2 == 0 { temp_flag |= Flags::P; }
+    if var_out.count_ones().is_multiple_of(2) { temp_flag |= Flags::P; }
 
     temp_flag.bits()
 }
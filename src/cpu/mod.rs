@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod cpu;
-mod instr;
+pub mod instr;
 pub mod sreg;
@@ -22,7 +22,7 @@ pub struct SregCoreState {
     _interrupt_causes: u16,
 }
 
-#[allow(non_camel_case_types)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 #[derive(enumn::N)]
 #[repr(u16)]
 pub enum SREG {
@@ -51,6 +51,7 @@ const JTR_INSTPG: u16 = 0b001;
 
 pub const IRQF_EXT: u16 = 1<<0;
 pub const IRQF_SYS: u16 = 1<<1;
+#[allow(dead_code)]
 pub const IRQF_MEM: u16 = 1<<3;
 
 impl SregCoreState {
@@ -66,6 +67,12 @@ impl SregCoreState {
         }
     }
 
+    /// Back to power-on state, paging off and privileged
+    pub fn reset(&mut self) {
+        *self = SregCoreState::new(self.coreid);
+    }
+
+    #[allow(clippy::collapsible_match)] // privilege checks kept separate from register decode
     pub fn write(&mut self, addr: u16, data: u16, cpu_state: &mut State) {
         match SREG::n(addr) {
             Some(SREG::PC)  => {
@@ -142,7 +149,7 @@ impl SregCoreState {
         }
         let addr_low: u32 = (addr & ((1<<11)-1)) as u32;
         let page: u32 = self.dmmu[(addr>>11) as usize] as u32;
-        println!("dmmu {:#06x} -> {:#08x}", addr, (page<<11)|addr_low);
+        crate::trace!("dmmu {:#06x} -> {:#08x}", addr, (page<<11)|addr_low);
         (page<<11) | addr_low
     }
 
@@ -153,9 +160,18 @@ impl SregCoreState {
         self._interrupt_causes |= cause;
     }
 
-    pub fn interrupt(&mut self, state: &mut State) {
+    pub fn is_privileged(&self) -> bool {
+        (self.sr1_priv & PRIV_PRIV) != 0
+    }
+
+    pub fn irq_enabled(&self) -> bool {
+        (self.sr1_priv & PRIV_IRQ) != 0
+    }
+
+    /// Enter interrupt handler if any cause is pending, returns true if interrupt was taken
+    pub fn interrupt(&mut self, state: &mut State) -> bool {
         if self._interrupt_causes == 0 {
-            return;
+            return false;
         }
 
         self.sr1_priv = PRIV_PRIV;
@@ -169,6 +185,7 @@ impl SregCoreState {
         self._interrupt_causes = 0;
 
         state.pc = 0x1;
+        true
     }
 
     pub fn irt(&mut self) -> u16 {
@@ -0,0 +1,157 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, Read, Write, BufWriter};
+use std::path::Path;
+
+use crate::cpu::cpu::{ExecEvent, ExecHook};
+use crate::cpu::instr::{Encoding, disassemble};
+use crate::debug::image_pc;
+use crate::debug::symbols::{SymbolTable, parse_addr};
+
+// Guest code coverage. Executed instructions are recorded by physical fetch address, so code of
+// different processes mapped at the same virtual address is counted separately.
+
+struct Hit {
+    instr: u32,
+    count: u64,
+}
+
+pub struct Coverage {
+    hits: BTreeMap<u32, Hit>,
+}
+
+impl ExecHook for Coverage {
+    fn executed(&mut self, ev: &ExecEvent) {
+        self.hits.entry(ev.phys_addr).or_insert(Hit { instr: ev.instr, count: 0 }).count += 1;
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage { hits: BTreeMap::new() }
+    }
+
+    /// Merge counts from coverage data file written by previous run. Missing file is not an error.
+    pub fn merge_file(&mut self, path: &Path) -> io::Result<()> {
+        let mut text = String::new();
+        match File::open(path) {
+            Ok(mut f) => f.read_to_string(&mut text)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        for (lineno, line) in text.lines().enumerate() {
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let parsed = match fields[..] {
+                [addr, instr, count] => parse_addr(addr).zip(parse_addr(instr)).zip(count.parse::<u64>().ok()),
+                _ => None,
+            };
+            let Some(((addr, instr), count)) = parsed else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("coverage data line {}: expected `addr instr count`", lineno+1)));
+            };
+            self.hits.entry(addr).or_insert(Hit { instr, count: 0 }).count += count;
+        }
+        Ok(())
+    }
+
+    pub fn write_data(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "# pcsn coverage: phys_addr instr count")?;
+        for (addr, hit) in &self.hits {
+            writeln!(out, "{:#08x} {:#010x} {}", addr, hit.instr, hit.count)?;
+        }
+        out.flush()
+    }
+
+    fn image_counts(&self) -> HashMap<u32, u64> {
+        let mut counts = HashMap::new();
+        for (addr, hit) in &self.hits {
+            if let Some(pc) = image_pc(*addr) {
+                *counts.entry(pc).or_insert(0) += hit.count;
+            }
+        }
+        counts
+    }
+
+    /// Write lcov tracefile, attributing image instructions to source lines from line map
+    pub fn write_lcov(&self, path: &Path, lines: &LineMap) -> io::Result<()> {
+        let image_counts = self.image_counts();
+
+        let mut files: BTreeMap<&str, BTreeMap<u32, u64>> = BTreeMap::new();
+        for (pc, (file, line)) in &lines.entries {
+            let count = files.entry(file.as_str()).or_default().entry(*line).or_insert(0);
+            *count += image_counts.get(pc).copied().unwrap_or(0);
+        }
+
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "TN:")?;
+        for (file, lines) in files {
+            writeln!(out, "SF:{}", file)?;
+            for (line, count) in &lines {
+                writeln!(out, "DA:{},{}", line, count)?;
+            }
+            writeln!(out, "LH:{}", lines.values().filter(|c| **c > 0).count())?;
+            writeln!(out, "LF:{}", lines.len())?;
+            writeln!(out, "end_of_record")?;
+        }
+        out.flush()
+    }
+
+    /// Write disassembly of program image with execution count of every instruction
+    pub fn write_listing(&self, path: &Path, prog: &[u8], symbols: &SymbolTable, lines: Option<&LineMap>) -> io::Result<()> {
+        let image_counts = self.image_counts();
+        let mut out = BufWriter::new(File::create(path)?);
+        let mut covered = 0;
+        let total = prog.len().div_ceil(4);
+
+        for (pc, word) in prog.chunks(4).enumerate() {
+            let pc = pc as u32;
+            let mut raw = [0; 4];
+            raw[..word.len()].copy_from_slice(word);
+            let instr = u32::from_le_bytes(raw);
+
+            if let Some(label) = symbols.label(pc) {
+                writeln!(out, "{:>10}   {}:", "", label)?;
+            }
+            let count = image_counts.get(&pc).copied().unwrap_or(0);
+            let count_str = if count == 0 { String::from("#####") } else { covered += 1; count.to_string() };
+            let source = lines.and_then(|l| l.entries.get(&pc)).map(|(f, l)| format!("  ; {}:{}", f, l)).unwrap_or_default();
+            writeln!(out, "{:>10}:  {:#06x} <{}>: {}{}", count_str, pc, symbols.format(pc),
+                     disassemble(&Encoding::from_raw(instr), symbols), source)?;
+        }
+        writeln!(out, "# {} of {} instructions executed", covered, total)?;
+        out.flush()
+    }
+}
+
+/// Mapping of image pc to source `file:line`, loaded from text file with `addr file:line` lines
+pub struct LineMap {
+    entries: BTreeMap<u32, (String, u32)>,
+}
+
+impl LineMap {
+    pub fn load(path: &Path) -> io::Result<LineMap> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+
+        let mut entries = BTreeMap::new();
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parsed = line.split_once(char::is_whitespace).and_then(|(addr, loc)| {
+                let (file, src_line) = loc.trim().rsplit_once(':')?;
+                Some((parse_addr(addr)?, String::from(file), src_line.parse().ok()?))
+            });
+            let Some((addr, file, src_line)) = parsed else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("line map line {}: expected `addr file:line`", lineno+1)));
+            };
+            entries.insert(addr, (file, src_line));
+        }
+        Ok(LineMap { entries })
+    }
+}
@@ -0,0 +1,15 @@
+pub mod symbols;
+pub mod profile;
+pub mod coverage;
+
+// Bus addresses with this bit set are in the instruction memory window (see `immu_translate`)
+const INSTR_WINDOW: u32 = 0x80_0000;
+
+/// Program image pc of instruction fetched from bus address, if it is in instruction memory window.
+/// Exact only for identity mapped (kernel) code, which is loaded at the window start.
+pub fn image_pc(phys_addr: u32) -> Option<u32> {
+    if phys_addr & INSTR_WINDOW == 0 {
+        return None;
+    }
+    Some((phys_addr & !INSTR_WINDOW) >> 1)
+}
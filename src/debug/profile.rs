@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write, BufWriter};
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::cpu::cpu::{ExecEvent, ExecHook};
+use crate::cpu::instr::Flow;
+use crate::debug::symbols::SymbolTable;
+
+// Guest profiler. Counts retired instructions per pc and keeps shadow call stack built from
+// `jal` and pc sreg writes (returns), with interrupt entries as separate frames.
+
+const MAX_DEPTH: usize = 256;
+const IRQ_FRAME: u32 = u32::MAX;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Mode {
+    Irq,
+    Kernel,
+    User,
+}
+
+const MODES: [Mode; 3] = [Mode::Irq, Mode::Kernel, Mode::User];
+
+struct Frame {
+    entry: u32,
+    ret: u16,
+}
+
+pub struct Profiler {
+    symbols: Rc<SymbolTable>,
+
+    total: u64,
+    counts: HashMap<(u16, Mode), u64>,
+
+    stack: Vec<Frame>,
+    stack_id: usize,
+    stack_ids: HashMap<Vec<u32>, usize>,
+    stack_counts: HashMap<(usize, u16), u64>,
+}
+
+impl ExecHook for Profiler {
+    fn executed(&mut self, ev: &ExecEvent) {
+        let mode = if !ev.privileged {
+            Mode::User
+        } else if self.stack.iter().any(|f| f.entry == IRQ_FRAME) {
+            Mode::Irq
+        } else {
+            Mode::Kernel
+        };
+
+        self.total += 1;
+        *self.counts.entry((ev.pc, mode)).or_insert(0) += 1;
+        *self.stack_counts.entry((self.stack_id, ev.pc)).or_insert(0) += 1;
+
+        let stack_len = self.stack.len();
+        match ev.flow {
+            Flow::Call => {
+                let target = (ev.instr >> 16) as u16;
+                self.push(Frame { entry: target as u32, ret: ev.pc.wrapping_add(1) });
+            },
+            Flow::Return => {
+                if let Some(pos) = self.stack.iter().rposition(|f| f.ret == ev.next_pc && f.entry != IRQ_FRAME) {
+                    self.stack.truncate(pos);
+                }
+            },
+            Flow::InterruptReturn => {
+                if let Some(pos) = self.stack.iter().rposition(|f| f.entry == IRQ_FRAME) {
+                    self.stack.truncate(pos);
+                }
+            },
+            _ => {},
+        }
+        if ev.interrupted {
+            self.push(Frame { entry: IRQ_FRAME, ret: ev.next_pc });
+        }
+
+        if self.stack.len() != stack_len || ev.flow == Flow::Call {
+            self.update_stack_id();
+        }
+    }
+}
+
+impl Profiler {
+    pub fn new(symbols: Rc<SymbolTable>) -> Profiler {
+        let mut stack_ids = HashMap::new();
+        stack_ids.insert(vec![], 0);
+        Profiler {
+            symbols, total: 0, counts: HashMap::new(),
+            stack: vec![], stack_id: 0, stack_ids, stack_counts: HashMap::new(),
+        }
+    }
+
+    fn push(&mut self, frame: Frame) {
+        if self.stack.len() >= MAX_DEPTH {
+            self.stack.remove(0);
+        }
+        self.stack.push(frame);
+    }
+
+    fn update_stack_id(&mut self) {
+        let key: Vec<u32> = self.stack.iter().map(|f| f.entry).collect();
+        let next_id = self.stack_ids.len();
+        self.stack_id = *self.stack_ids.entry(key).or_insert(next_id);
+    }
+
+    fn func_name(&self, addr: u32) -> String {
+        match self.symbols.lookup(addr) {
+            Some((name, _)) => String::from(name),
+            None => format!("{:#06x}", addr),
+        }
+    }
+
+    /// Write human readable report with instruction counts per mode, function and pc
+    pub fn write_report(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let percent = |n: u64| if self.total == 0 { 0.0 } else { n as f64 * 100.0 / self.total as f64 };
+
+        writeln!(out, "# pcsn profile, {} instructions", self.total)?;
+        writeln!(out)?;
+        writeln!(out, "# {:<8} {:>12} {:>8}", "mode", "instructions", "percent")?;
+        for (mode, name) in MODES.iter().zip(["irq", "kernel", "user"]) {
+            let n: u64 = self.counts.iter().filter(|((_, m), _)| m == mode).map(|(_, n)| n).sum();
+            writeln!(out, "  {:<8} {:>12} {:>7.2}%", name, n, percent(n))?;
+        }
+
+        let mut funcs: HashMap<String, [u64; 3]> = HashMap::new();
+        for ((pc, mode), n) in &self.counts {
+            let ent = funcs.entry(self.func_name(*pc as u32)).or_insert([0; 3]);
+            ent[MODES.iter().position(|m| m == mode).unwrap()] += n;
+        }
+        let mut funcs: Vec<_> = funcs.into_iter().collect();
+        funcs.sort_by(|a, b| b.1.iter().sum::<u64>().cmp(&a.1.iter().sum()).then(a.0.cmp(&b.0)));
+
+        writeln!(out)?;
+        writeln!(out, "# {:>12} {:>8} {:>12} {:>12} {:>12}  function", "instructions", "percent", "irq", "kernel", "user")?;
+        for (name, n) in &funcs {
+            let sum = n.iter().sum();
+            writeln!(out, "  {:>12} {:>7.2}% {:>12} {:>12} {:>12}  {}", sum, percent(sum), n[0], n[1], n[2], name)?;
+        }
+
+        let mut pcs: HashMap<u16, u64> = HashMap::new();
+        for ((pc, _), n) in &self.counts {
+            *pcs.entry(*pc).or_insert(0) += n;
+        }
+        let mut pcs: Vec<_> = pcs.into_iter().collect();
+        pcs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        writeln!(out)?;
+        writeln!(out, "# {:>12} {:>8}  pc", "instructions", "percent")?;
+        for (pc, n) in pcs {
+            writeln!(out, "  {:>12} {:>7.2}%  {:#06x} <{}>", n, percent(n), pc, self.symbols.format(pc as u32))?;
+        }
+        out.flush()
+    }
+
+    /// Write call stacks in collapsed format (`main;foo;bar 123`), as used by flamegraph tools
+    pub fn write_collapsed_stacks(&self, path: &Path) -> io::Result<()> {
+        let mut stacks: Vec<&[u32]> = vec![&[]; self.stack_ids.len()];
+        for (stack, id) in &self.stack_ids {
+            stacks[*id] = stack;
+        }
+
+        let mut folded: HashMap<String, u64> = HashMap::new();
+        for ((id, pc), n) in &self.stack_counts {
+            let mut names: Vec<String> = stacks[*id].iter()
+                .map(|&entry| if entry == IRQ_FRAME { String::from("[irq]") } else { self.func_name(entry) })
+                .collect();
+            let leaf = self.func_name(*pc as u32);
+            if names.last() != Some(&leaf) {
+                names.push(leaf);
+            }
+            *folded.entry(names.join(";")).or_insert(0) += n;
+        }
+
+        let mut folded: Vec<_> = folded.into_iter().collect();
+        folded.sort();
+
+        let mut out = BufWriter::new(File::create(path)?);
+        for (stack, n) in folded {
+            writeln!(out, "{} {}", stack, n)?;
+        }
+        out.flush()
+    }
+}
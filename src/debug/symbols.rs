@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+// Symbol table used to pretty-print code addresses (pc values, jump targets) as `func+0x12`.
+// Addresses are in the same units as the CPU pc, ie. 32 bit instruction words. ELF symbol values
+// are used as they are, so ELF files have to use the same word addresses.
+
+pub struct SymbolTable {
+    symbols: BTreeMap<u32, String>,
+}
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+const SHT_SYMTAB: u32 = 2;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable { symbols: BTreeMap::new() }
+    }
+
+    /// Load symbols either from ELF file (detected by magic) or from text map with `name addr` lines
+    pub fn load(path: &Path) -> io::Result<SymbolTable> {
+        let mut buff = Vec::new();
+        File::open(path)?.read_to_end(&mut buff)?;
+
+        if buff.starts_with(ELF_MAGIC) {
+            Self::from_elf(&buff)
+        } else {
+            Self::from_map(&String::from_utf8_lossy(&buff))
+        }
+    }
+
+    pub fn from_map(text: &str) -> io::Result<SymbolTable> {
+        let mut table = SymbolTable::new();
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let (Some(name), Some(addr)) = (fields.next(), fields.next()) else {
+                return Err(invalid_data(format!("symbol map line {}: expected `name addr`", lineno+1)));
+            };
+            let addr = parse_addr(addr)
+                .ok_or_else(|| invalid_data(format!("symbol map line {}: invalid address `{}`", lineno+1, addr)))?;
+            table.insert(addr, name);
+        }
+        Ok(table)
+    }
+
+    pub fn from_elf(elf: &[u8]) -> io::Result<SymbolTable> {
+        let rd = ElfReader::new(elf)?;
+        let mut table = SymbolTable::new();
+
+        let (shoff, shentsize, shnum) = if rd.is64 {
+            (rd.u64(0x28)? as usize, rd.u16(0x3a)? as usize, rd.u16(0x3c)? as usize)
+        } else {
+            (rd.u32(0x20)? as usize, rd.u16(0x2e)? as usize, rd.u16(0x30)? as usize)
+        };
+
+        for sec in 0..shnum {
+            let sh = rd.offset(shoff, sec, shentsize)?;
+            if rd.u32(sh + 0x4)? != SHT_SYMTAB {
+                continue;
+            }
+            let (offset, size, link, entsize) = if rd.is64 {
+                (rd.u64(sh + 0x18)? as usize, rd.u64(sh + 0x20)? as usize, rd.u32(sh + 0x28)? as usize, rd.u64(sh + 0x38)? as usize)
+            } else {
+                (rd.u32(sh + 0x10)? as usize, rd.u32(sh + 0x14)? as usize, rd.u32(sh + 0x18)? as usize, rd.u32(sh + 0x24)? as usize)
+            };
+            let strtab_sh = rd.offset(shoff, link, shentsize)?;
+            let strtab = if rd.is64 { rd.u64(strtab_sh + 0x18)? as usize } else { rd.u32(strtab_sh + 0x10)? as usize };
+
+            if entsize == 0 {
+                return Err(invalid_data(String::from("ELF symbol table with zero entry size")));
+            }
+            let end = rd.offset(offset, size, 1)?;
+            for sym in (offset..end).step_by(entsize) {
+                let (name, info, shndx, value) = if rd.is64 {
+                    (rd.u32(sym)?, rd.u8(sym + 0x4)?, rd.u16(sym + 0x6)?, rd.u64(sym + 0x8)?)
+                } else {
+                    (rd.u32(sym)?, rd.u8(sym + 0xc)?, rd.u16(sym + 0xe)?, rd.u32(sym + 0x4)? as u64)
+                };
+
+                let sym_type = info & 0xf;
+                if name == 0 || shndx == 0 || ![STT_NOTYPE, STT_OBJECT, STT_FUNC].contains(&sym_type) {
+                    continue;
+                }
+                table.insert(value as u32, rd.cstr(rd.offset(strtab, name as usize, 1)?)?);
+            }
+        }
+        Ok(table)
+    }
+
+    pub fn insert(&mut self, addr: u32, name: &str) {
+        // keep first name if there are aliases
+        self.symbols.entry(addr).or_insert_with(|| String::from(name));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Find nearest symbol at or below `addr`, returns its name and offset
+    pub fn lookup(&self, addr: u32) -> Option<(&str, u32)> {
+        self.symbols.range(..=addr).next_back()
+            .map(|(sym_addr, name)| (name.as_str(), addr - sym_addr))
+    }
+
+    /// Exact symbol name at `addr`, used for labels in disassembly
+    pub fn label(&self, addr: u32) -> Option<&str> {
+        self.symbols.get(&addr).map(|s| s.as_str())
+    }
+
+    /// Address formatted as `func+0x12`, or plain number if no symbol covers it
+    pub fn format(&self, addr: u32) -> String {
+        match self.lookup(addr) {
+            Some((name, 0)) => String::from(name),
+            Some((name, off)) => format!("{}+{:#x}", name, off),
+            None => format!("{}", addr),
+        }
+    }
+}
+
+pub fn parse_addr(s: &str) -> Option<u32> {
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+struct ElfReader<'a> {
+    data: &'a [u8],
+    is64: bool,
+    big_endian: bool,
+}
+
+impl ElfReader<'_> {
+    fn new(data: &[u8]) -> io::Result<ElfReader<'_>> {
+        if data.len() < 0x34 {
+            return Err(invalid_data(String::from("truncated ELF header")));
+        }
+        Ok(ElfReader { data, is64: data[4] == 2, big_endian: data[5] == 2 })
+    }
+
+    fn bytes<const N: usize>(&self, at: usize) -> io::Result<[u8; N]> {
+        let mut b: [u8; N] = at.checked_add(N).and_then(|end| self.data.get(at..end))
+            .ok_or_else(|| invalid_data(String::from("ELF offset out of range")))?
+            .try_into().unwrap();
+        if self.big_endian {
+            b.reverse();
+        }
+        Ok(b)
+    }
+
+    /// `base + index*size`, offsets come from the file so they are checked to stay within it
+    fn offset(&self, base: usize, index: usize, size: usize) -> io::Result<usize> {
+        index.checked_mul(size).and_then(|off| off.checked_add(base))
+            .filter(|at| *at <= self.data.len())
+            .ok_or_else(|| invalid_data(String::from("ELF offset out of range")))
+    }
+
+    fn u8(&self, at: usize) -> io::Result<u8> {
+        Ok(self.bytes::<1>(at)?[0])
+    }
+
+    fn u16(&self, at: usize) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(at)?))
+    }
+
+    fn u32(&self, at: usize) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(at)?))
+    }
+
+    fn u64(&self, at: usize) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(at)?))
+    }
+
+    fn cstr(&self, at: usize) -> io::Result<&str> {
+        let tail = self.data.get(at..).ok_or_else(|| invalid_data(String::from("ELF string out of range")))?;
+        let end = tail.iter().position(|&c| c == 0).unwrap_or(tail.len());
+        std::str::from_utf8(&tail[..end]).map_err(|_| invalid_data(String::from("invalid ELF symbol name")))
+    }
+}
@@ -1,9 +1,24 @@
 use std::rc::Rc;
 use std::cell::RefCell;
 
+use crate::debug::image_pc;
+use crate::debug::symbols::SymbolTable;
+use crate::support::trace;
+
 pub trait Device {
     fn read(&mut self, address: u32, sel: u8) -> u16;
     fn write(&mut self, address: u32, sel: u8, data: u16);
+
+    /// Called once per executed instruction, for devices that advance with simulated time
+    fn tick(&mut self) {}
+
+    /// System reset, registers return to power-on state (memory contents are kept)
+    fn reset(&mut self) {}
+}
+
+/// Device that initiates its own bus cycles (DMA), runs after devices are ticked
+pub trait BusMaster {
+    fn master_tick(&mut self, bus: &mut Bus);
 }
 
 pub struct DeviceEntry {
@@ -13,7 +28,9 @@ pub struct DeviceEntry {
 }
 
 pub struct Bus {
-    devices: Vec<DeviceEntry>
+    devices: Vec<DeviceEntry>,
+    masters: Vec<Rc<RefCell<dyn BusMaster>>>,
+    symbols: Rc<SymbolTable>,
 }
 
 impl Bus {
@@ -21,6 +38,10 @@ impl Bus {
          self.devices.push(dev_ent);
     }
 
+    pub fn add_master(&mut self, master: Rc<RefCell<dyn BusMaster>>) {
+        self.masters.push(master);
+    }
+
     fn find_device(&mut self, addr: u32) -> Option<&mut DeviceEntry> {
         for dev in &mut self.devices {
             if addr >= dev.begin_addr && addr <= dev.end_addr {
@@ -30,24 +51,57 @@ impl Bus {
         None
     }
 
-    pub fn new() -> Bus {
-        Bus { devices: vec![] }
+    /// True if bus master can access address: it is mapped and device isn't the master itself
+    pub fn can_access(&self, addr: u32) -> bool {
+        // master being ticked is mutably borrowed for the whole master_tick
+        self.devices.iter()
+            .find(|dev| addr >= dev.begin_addr && addr <= dev.end_addr)
+            .is_some_and(|dev| dev.device.try_borrow_mut().is_ok())
+    }
+
+    fn addr_repr(&self, addr: u32) -> String {
+        match image_pc(addr) {
+            Some(pc) if !self.symbols.is_empty() => format!(" <{}>", self.symbols.format(pc)),
+            _ => String::new(),
+        }
+    }
+
+    pub fn new(symbols: Rc<SymbolTable>) -> Bus {
+        Bus { devices: vec![], masters: vec![], symbols }
     }
 }
 
 impl Device for Bus {
     fn read(&mut self, address: u32, sel: u8) -> u16 {
-        print!("Bus read addr={:#08x}, sel={}", address, sel);
+        if trace::enabled() {
+            print!("Bus read addr={:#08x}{}, sel={}", address, self.addr_repr(address), sel);
+        }
         let dev = self.find_device(address).unwrap(); // TODO: Support bus err respose in some
                                                       // cases and panic in others
         let r = dev.device.borrow_mut().read(address-dev.begin_addr, sel);
-        println!("  resp={:#05x}", r);
+        crate::trace!("  resp={:#05x}", r);
         r
     }
      
     fn write(&mut self, address: u32, sel: u8, data: u16) {
-        println!("Bus write addr={:#08x}, sel={}, data={}", address, sel, data);
+        crate::trace!("Bus write addr={:#08x}{}, sel={}, data={}", address, self.addr_repr(address), sel, data);
         let dev = self.find_device(address).unwrap();
         dev.device.borrow_mut().write(address-dev.begin_addr, sel, data) 
     }
+
+    fn tick(&mut self) {
+        for dev in &self.devices {
+            dev.device.borrow_mut().tick();
+        }
+        // masters check addresses with can_access, own registers can't be borrowed while ticking
+        for master in self.masters.clone() {
+            master.borrow_mut().master_tick(self);
+        }
+    }
+
+    fn reset(&mut self) {
+        for dev in &self.devices {
+            dev.device.borrow_mut().reset();
+        }
+    }
 }
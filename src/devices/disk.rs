@@ -0,0 +1,268 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::rc::Rc;
+
+// Block storage behind emulated SD card. Base image can be written directly, or kept untouched with
+// copy-on-write overlay, where written blocks go to sparse delta (in memory or in overlay file) and
+// reads prefer delta blocks over the base image. Base can also be generated image kept in memory,
+// shared with the host side so it can be inspected after the run.
+//
+// Overlay file format: magic header followed by records of block number (u32 LE) and block data.
+// Record of block written again is updated in place, so file holds at most one record per block.
+
+pub const BLOCK_SIZE: usize = 512;
+
+const OVERLAY_MAGIC: &[u8; 8] = b"PCSNOVL1";
+const RECORD_SIZE: u64 = 4 + BLOCK_SIZE as u64;
+
+pub struct DiskImage {
+    base: Base,
+    blocks: u64,
+    overlay: Option<Overlay>,
+}
+
+enum Base {
+    File(File),
+    Memory(Rc<RefCell<Vec<u8>>>),
+}
+
+pub struct Overlay {
+    blocks: HashMap<u32, OverlayBlock>,
+    file: Option<File>,
+    file_len: u64,
+}
+
+struct OverlayBlock {
+    data: Box<[u8; BLOCK_SIZE]>,
+    record_offset: u64,
+}
+
+impl DiskImage {
+    /// Image with writes going directly to base file
+    pub fn direct(base: File) -> io::Result<DiskImage> {
+        let blocks = base.metadata()?.len() / BLOCK_SIZE as u64;
+        Ok(DiskImage { base: Base::File(base), blocks, overlay: None })
+    }
+
+    /// Image kept in memory, writes modify the shared buffer
+    pub fn memory(data: Rc<RefCell<Vec<u8>>>) -> DiskImage {
+        let blocks = data.borrow().len() as u64 / BLOCK_SIZE as u64;
+        DiskImage { base: Base::Memory(data), blocks, overlay: None }
+    }
+
+    /// Image with writes going to overlay, base file is only read
+    pub fn with_overlay(base: File, overlay: Overlay) -> io::Result<DiskImage> {
+        let blocks = base.metadata()?.len() / BLOCK_SIZE as u64;
+        Ok(DiskImage { base: Base::File(base), blocks, overlay: Some(overlay) })
+    }
+
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    pub fn read_block(&mut self, block: u32, buf: &mut [u8; BLOCK_SIZE]) -> io::Result<()> {
+        if let Some(overlay_block) = self.overlay.as_ref().and_then(|o| o.blocks.get(&block)) {
+            buf.copy_from_slice(overlay_block.data.as_slice());
+            return Ok(());
+        }
+        self.base.read_block(block, buf)
+    }
+
+    pub fn write_block(&mut self, block: u32, data: &[u8; BLOCK_SIZE]) -> io::Result<()> {
+        match &mut self.overlay {
+            Some(overlay) => overlay.write_block(block, data),
+            None => self.base.write_block(block, data),
+        }
+    }
+
+    /// Fold overlay blocks back to base image (which must be opened for writing) and clear the overlay
+    pub fn commit(&mut self) -> io::Result<usize> {
+        let Some(overlay) = &mut self.overlay else {
+            return Ok(0);
+        };
+        let mut blocks: Vec<_> = overlay.blocks.iter().collect();
+        blocks.sort_by_key(|(block, _)| **block);
+        for (block, overlay_block) in &blocks {
+            self.base.write_block(**block, &overlay_block.data)?;
+        }
+        if let Base::File(file) = &self.base {
+            file.sync_data()?;
+        }
+
+        let count = blocks.len();
+        overlay.clear()?;
+        Ok(count)
+    }
+}
+
+impl Base {
+    fn read_block(&mut self, block: u32, buf: &mut [u8; BLOCK_SIZE]) -> io::Result<()> {
+        match self {
+            Base::File(file) => {
+                file.seek(SeekFrom::Start(block as u64 * BLOCK_SIZE as u64))?;
+                file.read_exact(buf)
+            },
+            Base::Memory(data) => {
+                let offset = block as usize * BLOCK_SIZE;
+                buf.copy_from_slice(&data.borrow()[offset..offset+BLOCK_SIZE]);
+                Ok(())
+            },
+        }
+    }
+
+    fn write_block(&mut self, block: u32, buf: &[u8; BLOCK_SIZE]) -> io::Result<()> {
+        match self {
+            Base::File(file) => {
+                file.seek(SeekFrom::Start(block as u64 * BLOCK_SIZE as u64))?;
+                file.write_all(buf)
+            },
+            Base::Memory(data) => {
+                let offset = block as usize * BLOCK_SIZE;
+                data.borrow_mut()[offset..offset+BLOCK_SIZE].copy_from_slice(buf);
+                Ok(())
+            },
+        }
+    }
+}
+
+impl Overlay {
+    pub fn in_memory() -> Overlay {
+        Overlay { blocks: HashMap::new(), file: None, file_len: 0 }
+    }
+
+    /// Open overlay file, loading blocks written by previous runs. Missing or empty file starts empty overlay.
+    pub fn open(mut file: File) -> io::Result<Overlay> {
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+
+        let mut overlay = Overlay { blocks: HashMap::new(), file: None, file_len: 0 };
+        if content.is_empty() {
+            file.write_all(OVERLAY_MAGIC)?;
+        } else if !content.starts_with(OVERLAY_MAGIC) || !((content.len() - OVERLAY_MAGIC.len()) as u64).is_multiple_of(RECORD_SIZE) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a pcsn SD overlay file"));
+        }
+
+        for (i, record) in content[OVERLAY_MAGIC.len().min(content.len())..].chunks(RECORD_SIZE as usize).enumerate() {
+            let block = u32::from_le_bytes(record[..4].try_into().unwrap());
+            let data = Box::new(record[4..].try_into().unwrap());
+            let record_offset = OVERLAY_MAGIC.len() as u64 + i as u64 * RECORD_SIZE;
+            overlay.blocks.insert(block, OverlayBlock { data, record_offset });
+        }
+
+        overlay.file_len = content.len().max(OVERLAY_MAGIC.len()) as u64;
+        overlay.file = Some(file);
+        Ok(overlay)
+    }
+
+    fn write_block(&mut self, block: u32, data: &[u8; BLOCK_SIZE]) -> io::Result<()> {
+        let record_offset = match self.blocks.get(&block) {
+            Some(overlay_block) => overlay_block.record_offset,
+            None => {
+                self.file_len += RECORD_SIZE;
+                self.file_len - RECORD_SIZE
+            },
+        };
+
+        if let Some(file) = &mut self.file {
+            file.seek(SeekFrom::Start(record_offset))?;
+            file.write_all(&block.to_le_bytes())?;
+            file.write_all(data)?;
+        }
+        self.blocks.insert(block, OverlayBlock { data: Box::new(*data), record_offset });
+        Ok(())
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.blocks.clear();
+        self.file_len = OVERLAY_MAGIC.len() as u64;
+        if let Some(file) = &mut self.file {
+            file.set_len(self.file_len)?;
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pcsn-disk-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn open_rw(path: &Path) -> File {
+        File::options().read(true).write(true).create(true).truncate(false).open(path).unwrap()
+    }
+
+    fn read(disk: &mut DiskImage, block: u32) -> [u8; BLOCK_SIZE] {
+        let mut buf = [0; BLOCK_SIZE];
+        disk.read_block(block, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn overlay_reopen_and_rewrite() {
+        let dir = temp_dir("reopen");
+        fs::write(dir.join("base.img"), vec![0; 4 * BLOCK_SIZE]).unwrap();
+
+        let mut disk = DiskImage::with_overlay(open_rw(&dir.join("base.img")), Overlay::open(open_rw(&dir.join("ovl"))).unwrap()).unwrap();
+        disk.write_block(1, &[1; BLOCK_SIZE]).unwrap();
+        disk.write_block(3, &[3; BLOCK_SIZE]).unwrap();
+        let len = fs::metadata(dir.join("ovl")).unwrap().len();
+        assert_eq!(len, OVERLAY_MAGIC.len() as u64 + 2 * RECORD_SIZE);
+        // rewritten block updates its record in place
+        disk.write_block(1, &[7; BLOCK_SIZE]).unwrap();
+        assert_eq!(fs::metadata(dir.join("ovl")).unwrap().len(), len);
+        drop(disk);
+
+        let mut disk = DiskImage::with_overlay(open_rw(&dir.join("base.img")), Overlay::open(open_rw(&dir.join("ovl"))).unwrap()).unwrap();
+        assert_eq!(read(&mut disk, 0), [0; BLOCK_SIZE]);
+        assert_eq!(read(&mut disk, 1), [7; BLOCK_SIZE]);
+        assert_eq!(read(&mut disk, 3), [3; BLOCK_SIZE]);
+        assert_eq!(fs::read(dir.join("base.img")).unwrap(), vec![0; 4 * BLOCK_SIZE]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn overlay_rejects_truncated_and_foreign_files() {
+        let dir = temp_dir("reject");
+        let mut truncated = OVERLAY_MAGIC.to_vec();
+        truncated.extend([0; 100]);
+        fs::write(dir.join("truncated"), truncated).unwrap();
+        fs::write(dir.join("foreign"), vec![0xaa; 8 + RECORD_SIZE as usize]).unwrap();
+        fs::write(dir.join("short"), b"PCSN").unwrap();
+
+        for name in ["truncated", "foreign", "short"] {
+            let err = Overlay::open(open_rw(&dir.join(name))).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", name);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn commit_folds_overlay_into_base() {
+        let dir = temp_dir("commit");
+        fs::write(dir.join("base.img"), vec![0; 4 * BLOCK_SIZE]).unwrap();
+
+        let mut disk = DiskImage::with_overlay(open_rw(&dir.join("base.img")), Overlay::open(open_rw(&dir.join("ovl"))).unwrap()).unwrap();
+        disk.write_block(2, &[2; BLOCK_SIZE]).unwrap();
+        disk.write_block(0, &[9; BLOCK_SIZE]).unwrap();
+        assert_eq!(disk.commit().unwrap(), 2);
+
+        let base = fs::read(dir.join("base.img")).unwrap();
+        assert_eq!(base[..BLOCK_SIZE], [9; BLOCK_SIZE]);
+        assert_eq!(base[BLOCK_SIZE..2*BLOCK_SIZE], [0; BLOCK_SIZE]);
+        assert_eq!(base[2*BLOCK_SIZE..3*BLOCK_SIZE], [2; BLOCK_SIZE]);
+        assert_eq!(fs::read(dir.join("ovl")).unwrap(), OVERLAY_MAGIC);
+        assert_eq!(read(&mut disk, 2), [2; BLOCK_SIZE]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+use super::bus::{Bus, BusMaster, Device};
+use super::irqc::IrqLine;
+
+// Single channel DMA engine copying LEN units from SRC to DST bus addresses. Addresses are byte
+// addresses on the bus (bus word address << 1 | byte lane), so byte transfers select one lane with
+// `sel` like CPU byte accesses do. Word transfers ignore the lowest address bit.
+// SRC_FIXED/DST_FIXED keep the address constant, for device data ports. Reading SPI RX_CLOCK
+// register clocks a dummy byte, so fixed source at SPI RX_CLOCK reads a block from SD card into RAM.
+//
+// One unit is moved per executed instruction, alongside the CPU. There is no cycle timing model in
+// the emulator, so the CPU is not stalled for the stolen bus cycles. SRC, DST and LEN registers
+// advance during transfer, LEN writes are ignored while busy. Transfer touching unmapped address or
+// DMA's own registers stops with ERROR and DONE set.
+
+pub struct Dma {
+    src: u32,
+    dst: u32,
+    len: u16,
+    ctrl: u16,
+    irq: IrqLine,
+}
+
+const SRC_LO_ADDR: u32 = 0x0;
+const SRC_HI_ADDR: u32 = 0x1;
+const DST_LO_ADDR: u32 = 0x2;
+const DST_HI_ADDR: u32 = 0x3;
+const LEN_ADDR: u32 = 0x4;
+const CTRL_ADDR: u32 = 0x5;
+
+const CTRL_START: u16 = 0b000001; // reads 1 while busy, write 0 to abort
+const CTRL_IRQ_EN: u16 = 0b000010;
+const CTRL_DONE: u16 = 0b000100; // write 1 to clear
+const CTRL_BYTE: u16 = 0b001000; // transfer bytes instead of words
+const CTRL_SRC_FIXED: u16 = 0b010000;
+const CTRL_DST_FIXED: u16 = 0b100000;
+const CTRL_ERROR: u16 = 0b1000000; // bus error, cleared by next start
+
+const ADDR_MASK: u32 = 0x1ff_ffff; // 24 bit word addresses
+
+impl Device for Dma {
+    fn read(&mut self, addr: u32, _sel: u8) -> u16 {
+        match addr {
+            SRC_LO_ADDR => self.src as u16,
+            SRC_HI_ADDR => (self.src >> 16) as u16,
+            DST_LO_ADDR => self.dst as u16,
+            DST_HI_ADDR => (self.dst >> 16) as u16,
+            LEN_ADDR => self.len,
+            CTRL_ADDR => self.ctrl,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u32, _sel: u8, data: u16) {
+        match addr {
+            SRC_LO_ADDR => { self.src = (self.src & 0xffff_0000) | data as u32; },
+            SRC_HI_ADDR => { self.src = ((self.src & 0xffff) | ((data as u32) << 16)) & ADDR_MASK; },
+            DST_LO_ADDR => { self.dst = (self.dst & 0xffff_0000) | data as u32; },
+            DST_HI_ADDR => { self.dst = ((self.dst & 0xffff) | ((data as u32) << 16)) & ADDR_MASK; },
+            LEN_ADDR if self.ctrl & CTRL_START == 0 => { self.len = data; },
+            CTRL_ADDR => {
+                let done = self.ctrl & !data & CTRL_DONE;
+                let error = if data & CTRL_START != 0 { 0 } else { self.ctrl & CTRL_ERROR };
+                self.ctrl = (data & !(CTRL_DONE | CTRL_ERROR)) | done | error;
+                if self.ctrl & CTRL_START != 0 && self.len == 0 {
+                    self.finish();
+                }
+                self.update_irq();
+            },
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        self.src = 0;
+        self.dst = 0;
+        self.len = 0;
+        self.ctrl = 0;
+        self.update_irq();
+    }
+}
+
+impl BusMaster for Dma {
+    fn master_tick(&mut self, bus: &mut Bus) {
+        if self.ctrl & CTRL_START == 0 {
+            return;
+        }
+
+        let byte = self.ctrl & CTRL_BYTE != 0;
+        let (src_adr, src_sel) = lane(self.src, byte);
+        let (dst_adr, dst_sel) = lane(self.dst, byte);
+        if !bus.can_access(src_adr) || !bus.can_access(dst_adr) {
+            self.ctrl |= CTRL_ERROR;
+            self.finish();
+            self.update_irq();
+            return;
+        }
+        let mut data = bus.read(src_adr, src_sel);
+        if byte {
+            // bus returns whole word, writes take byte in low bits (as in CPU byte load/store)
+            data = (data >> ((self.src & 1) * 8)) & 0xff;
+        }
+        bus.write(dst_adr, dst_sel, data);
+
+        let step = if byte { 1 } else { 2 };
+        if self.ctrl & CTRL_SRC_FIXED == 0 {
+            self.src = (self.src + step) & ADDR_MASK;
+        }
+        if self.ctrl & CTRL_DST_FIXED == 0 {
+            self.dst = (self.dst + step) & ADDR_MASK;
+        }
+        self.len -= 1;
+        if self.len == 0 {
+            self.finish();
+            self.update_irq();
+        }
+    }
+}
+
+impl Dma {
+    pub fn new(irq: IrqLine) -> Dma {
+        Dma { src: 0, dst: 0, len: 0, ctrl: 0, irq }
+    }
+
+    fn finish(&mut self) {
+        self.ctrl = (self.ctrl & !CTRL_START) | CTRL_DONE;
+    }
+
+    fn update_irq(&self) {
+        self.irq.set((self.ctrl & CTRL_IRQ_EN) != 0 && (self.ctrl & CTRL_DONE) != 0);
+    }
+}
+
+fn lane(byte_addr: u32, byte: bool) -> (u32, u8) {
+    let sel = if byte { 0b01 << (byte_addr & 1) } else { 0b11 };
+    (byte_addr >> 1, sel)
+}
@@ -0,0 +1,246 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use super::bus::Device;
+use super::font::{FONT_8X8, FONT_FIRST_CHAR};
+use super::ram::RAM;
+use crate::support::image::{Image, ImageFormat};
+use crate::support::tty::Pty;
+
+// VGA output with separate video RAM device. In text mode VRAM holds 80x30 words of character
+// (low byte) and attribute (high byte: foreground color index in low nibble, background in high).
+// Characters come from 8x8 character ROM with doubled rows, giving 640x480 picture. In pixel mode
+// VRAM holds 320x240 8-bit palette indices, two pixels per word (left pixel in low byte).
+// Palette entries are 12-bit 0xRGB colors. Frames are produced every FRAME_INSTRUCTIONS instructions.
+
+pub struct Framebuffer {
+    vram: Rc<RefCell<RAM>>,
+    ctrl: u16,
+    palette: [u16; 256],
+    palette_index: u8,
+
+    frame: u64,
+    frame_cycles: u64,
+
+    output: FramebufferOutput,
+    snapshots: u64,
+    last_term_text: Vec<u16>,
+}
+
+/// Host side outputs of the framebuffer
+pub struct FramebufferOutput {
+    pub snapshot_prefix: Option<PathBuf>, // snapshot files are named `<prefix><number>.<ext>`
+    pub format: ImageFormat,
+    pub every_frames: Option<u64>,
+    pub term: Option<Pty>, // text mode screen mirrored to terminal
+}
+
+const CTRL_ADDR: u32 = 0x0;
+const FRAME_ADDR: u32 = 0x1; // frame counter, low 16 bits
+const PALETTE_INDEX_ADDR: u32 = 0x2;
+const PALETTE_DATA_ADDR: u32 = 0x3; // access increments palette index
+const SNAPSHOT_ADDR: u32 = 0x4; // write requests snapshot of current picture
+
+const CTRL_ENABLE: u16 = 0b01;
+const CTRL_PIXEL_MODE: u16 = 0b10;
+
+pub const VRAM_WORDS: usize = 0x10000;
+
+pub const TEXT_COLS: usize = 80;
+pub const TEXT_ROWS: usize = 30;
+const CHAR_WIDTH: usize = 8;
+const CHAR_HEIGHT: usize = 16;
+const DEFAULT_ATTR: u8 = 0x07; // used for attribute 0, so plain ASCII is visible
+pub const PIXEL_WIDTH: usize = 320;
+pub const PIXEL_HEIGHT: usize = 240;
+
+const FRAME_INSTRUCTIONS: u64 = 400_000; // ~60 Hz at 25 MHz with one instruction per cycle
+
+impl Device for Framebuffer {
+    fn read(&mut self, addr: u32, _sel: u8) -> u16 {
+        match addr {
+            CTRL_ADDR => self.ctrl,
+            FRAME_ADDR => self.frame as u16,
+            PALETTE_INDEX_ADDR => self.palette_index as u16,
+            PALETTE_DATA_ADDR => {
+                let color = self.palette[self.palette_index as usize];
+                self.palette_index = self.palette_index.wrapping_add(1);
+                color
+            },
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u32, _sel: u8, data: u16) {
+        match addr {
+            CTRL_ADDR => { self.ctrl = data & (CTRL_ENABLE | CTRL_PIXEL_MODE); },
+            PALETTE_INDEX_ADDR => { self.palette_index = data as u8; },
+            PALETTE_DATA_ADDR => {
+                self.palette[self.palette_index as usize] = data & 0xfff;
+                self.palette_index = self.palette_index.wrapping_add(1);
+            },
+            SNAPSHOT_ADDR => self.snapshot(),
+            _ => {},
+        }
+    }
+
+    fn tick(&mut self) {
+        self.frame_cycles += 1;
+        if self.frame_cycles < FRAME_INSTRUCTIONS {
+            return;
+        }
+        self.frame_cycles = 0;
+        self.frame += 1;
+
+        if self.output.every_frames.is_some_and(|n| self.frame.is_multiple_of(n)) {
+            self.snapshot();
+        }
+        if self.output.term.is_some() {
+            self.update_term();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ctrl = 0;
+        self.palette = default_palette();
+        self.palette_index = 0;
+    }
+}
+
+impl Framebuffer {
+    pub fn new(vram: Rc<RefCell<RAM>>, output: FramebufferOutput) -> Framebuffer {
+        Framebuffer {
+            vram, ctrl: 0, palette: default_palette(), palette_index: 0,
+            frame: 0, frame_cycles: 0,
+            output, snapshots: 0, last_term_text: Vec::new(),
+        }
+    }
+
+    fn rgb(&self, index: u8) -> [u8; 3] {
+        let color = self.palette[index as usize];
+        [((color >> 8) & 0xf) as u8 * 17, ((color >> 4) & 0xf) as u8 * 17, (color & 0xf) as u8 * 17]
+    }
+
+    fn text_cell(&self, vram: &[u16], col: usize, row: usize) -> (u8, u8, u8) {
+        let word = vram[row * TEXT_COLS + col];
+        let attr = match (word >> 8) as u8 { 0 => DEFAULT_ATTR, attr => attr };
+        (word as u8, attr & 0xf, attr >> 4)
+    }
+
+    /// Render current picture, disabled output is black
+    pub fn render(&self) -> Image {
+        let vram = self.vram.borrow();
+        let vram = vram.words();
+
+        if self.ctrl & CTRL_PIXEL_MODE != 0 {
+            let mut image = Image::new(PIXEL_WIDTH, PIXEL_HEIGHT);
+            if self.ctrl & CTRL_ENABLE != 0 {
+                for y in 0..PIXEL_HEIGHT {
+                    for x in 0..PIXEL_WIDTH {
+                        let word = vram[(y * PIXEL_WIDTH + x) / 2];
+                        let index = if x % 2 == 0 { word as u8 } else { (word >> 8) as u8 };
+                        image.set_pixel(x, y, self.rgb(index));
+                    }
+                }
+            }
+            return image;
+        }
+
+        let mut image = Image::new(TEXT_COLS * CHAR_WIDTH, TEXT_ROWS * CHAR_HEIGHT);
+        if self.ctrl & CTRL_ENABLE == 0 {
+            return image;
+        }
+        for row in 0..TEXT_ROWS {
+            for col in 0..TEXT_COLS {
+                let (ch, fg, bg) = self.text_cell(vram, col, row);
+                let glyph = ch.checked_sub(FONT_FIRST_CHAR).and_then(|i| FONT_8X8.get(i as usize)).unwrap_or(&FONT_8X8[0]);
+                for y in 0..CHAR_HEIGHT {
+                    let bits = glyph[y / 2];
+                    for x in 0..CHAR_WIDTH {
+                        let set = bits & (0x80 >> x) != 0;
+                        image.set_pixel(col * CHAR_WIDTH + x, row * CHAR_HEIGHT + y, self.rgb(if set { fg } else { bg }));
+                    }
+                }
+            }
+        }
+        image
+    }
+
+    /// Write picture to next snapshot file
+    pub fn snapshot(&mut self) {
+        let Some(prefix) = &self.output.snapshot_prefix else {
+            println!("VGA snapshot requested, but snapshot output is not configured");
+            return;
+        };
+        let path = PathBuf::from(format!("{}{:04}.{}", prefix.display(), self.snapshots, self.output.format.extension()));
+        self.snapshots += 1;
+        self.write_snapshot(&path);
+    }
+
+    pub fn write_snapshot(&self, path: &std::path::Path) {
+        // requested by guest or monitor, bad path must not end the run
+        match self.render().write(path, self.output.format) {
+            Ok(()) => println!("VGA snapshot of frame {} written to {}", self.frame, path.display()),
+            Err(e) => println!("Failed to write VGA snapshot {}: {}", path.display(), e),
+        }
+    }
+
+    /// Final snapshot at exit, named `<prefix>final.<ext>`
+    pub fn final_snapshot(&self) {
+        if let Some(prefix) = &self.output.snapshot_prefix {
+            self.write_snapshot(&PathBuf::from(format!("{}final.{}", prefix.display(), self.output.format.extension())));
+        }
+    }
+
+    // redraw terminal with ANSI escapes when text changes
+    fn update_term(&mut self) {
+        let text_mode = self.ctrl & (CTRL_ENABLE | CTRL_PIXEL_MODE) == CTRL_ENABLE;
+        let text: Vec<u16> = if text_mode { self.vram.borrow().words()[..TEXT_COLS * TEXT_ROWS].to_vec() } else { Vec::new() };
+        if text == self.last_term_text {
+            return;
+        }
+
+        let mut out = String::from("\x1b[H\x1b[2J");
+        if text_mode {
+            for row in 0..TEXT_ROWS {
+                for col in 0..TEXT_COLS {
+                    let (ch, fg, bg) = self.text_cell(&text, col, row);
+                    let [fr, fgr, fb] = self.rgb(fg);
+                    let [br, bgr, bb] = self.rgb(bg);
+                    let ch = if (0x20..0x7f).contains(&ch) { ch as char } else { ' ' };
+                    out += &format!("\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m{}", fr, fgr, fb, br, bgr, bb, ch);
+                }
+                out += "\x1b[0m";
+                if row != TEXT_ROWS-1 {
+                    out += "\r\n";
+                }
+            }
+        }
+        self.last_term_text = text;
+
+        let term = self.output.term.as_mut().unwrap();
+        term.master_write_file.write_all(out.as_bytes()).expect("Failed to write VGA terminal");
+    }
+}
+
+fn default_palette() -> [u16; 256] {
+    const VGA_COLORS: [u16; 16] = [
+        0x000, 0x00a, 0x0a0, 0x0aa, 0xa00, 0xa0a, 0xa50, 0xaaa,
+        0x555, 0x55f, 0x5f5, 0x5ff, 0xf55, 0xf5f, 0xff5, 0xfff,
+    ];
+    const CUBE_LEVELS: [u16; 6] = [0x0, 0x3, 0x6, 0x9, 0xc, 0xf];
+
+    // same layout as xterm 256 colors: 16 base colors, 6x6x6 color cube and gray ramp
+    let mut palette = [0; 256];
+    palette[..16].copy_from_slice(&VGA_COLORS);
+    for i in 0..216 {
+        palette[16 + i] = (CUBE_LEVELS[i / 36] << 8) | (CUBE_LEVELS[(i / 6) % 6] << 4) | CUBE_LEVELS[i % 6];
+    }
+    for i in 0..24 {
+        let level = ((i * 15 + 11) / 23) as u16;
+        palette[232 + i] = (level << 8) | (level << 4) | level;
+    }
+    palette
+}
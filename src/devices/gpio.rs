@@ -0,0 +1,167 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::rc::Rc;
+
+use super::bus::Device;
+use super::irqc::IrqLine;
+use crate::debug::symbols::parse_addr;
+use crate::support::replay::{InputLog, InputEvent};
+
+// 16 pin GPIO port, as connected to board LEDs and switches. Pins configured as outputs read back
+// their output value. Any change of input pin value with enabled interrupt sets its bit in IRQ_STATUS.
+// Output changes are printed as status line, inputs are driven by the host (CLI, script or monitor).
+
+pub struct Gpio {
+    dir: u16,
+    out: u16,
+    ext_in: u16,
+    irq_en: u16,
+    irq_status: u16,
+    log: Rc<RefCell<InputLog>>,
+    irq: IrqLine,
+}
+
+const DIR_ADDR: u32 = 0x0; // 1 - output
+const OUT_ADDR: u32 = 0x1;
+const IN_ADDR: u32 = 0x2;
+const IRQ_EN_ADDR: u32 = 0x3;
+const IRQ_STATUS_ADDR: u32 = 0x4; // write 1 to clear
+
+pub const GPIO_PINS: u8 = 16;
+
+impl Device for Gpio {
+    fn read(&mut self, addr: u32, _sel: u8) -> u16 {
+        match addr {
+            DIR_ADDR => self.dir,
+            OUT_ADDR => self.out,
+            IN_ADDR => self.pins(),
+            IRQ_EN_ADDR => self.irq_en,
+            IRQ_STATUS_ADDR => self.irq_status,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u32, _sel: u8, data: u16) {
+        let old_outputs = self.outputs();
+        match addr {
+            DIR_ADDR => { self.dir = data; },
+            OUT_ADDR => { self.out = data; },
+            IRQ_EN_ADDR => { self.irq_en = data; },
+            IRQ_STATUS_ADDR => { self.irq_status &= !data; },
+            _ => {},
+        }
+        if self.outputs() != old_outputs {
+            println!("{}", self.status_line());
+        }
+        self.update_irq();
+    }
+
+    fn reset(&mut self) {
+        let old_outputs = self.outputs();
+        self.dir = 0;
+        self.out = 0;
+        self.irq_en = 0;
+        self.irq_status = 0;
+        if self.outputs() != old_outputs {
+            println!("{}", self.status_line());
+        }
+        self.update_irq();
+    }
+}
+
+impl Gpio {
+    pub fn new(ext_in: u16, log: Rc<RefCell<InputLog>>, irq: IrqLine) -> Gpio {
+        Gpio { dir: 0, out: 0, ext_in, irq_en: 0, irq_status: 0, log, irq }
+    }
+
+    /// Drive input pin from the host side
+    pub fn set_input(&mut self, pin: u8, level: bool) {
+        self.log.borrow_mut().input(&InputEvent::Gpio(pin, level));
+        let bit = 1 << pin;
+        let ext_in = if level { self.ext_in | bit } else { self.ext_in & !bit };
+        let changed = (ext_in ^ self.ext_in) & !self.dir;
+        self.ext_in = ext_in;
+        self.irq_status |= changed & self.irq_en;
+        self.update_irq();
+    }
+
+    fn pins(&self) -> u16 {
+        (self.out & self.dir) | (self.ext_in & !self.dir)
+    }
+
+    fn outputs(&self) -> u16 {
+        self.out & self.dir
+    }
+
+    /// Pin states, `*` - output high (lit LED), `.` - output low, `1`/`0` - input; pin 15 first
+    pub fn status_line(&self) -> String {
+        let pins: String = (0..GPIO_PINS).rev().map(|pin| {
+            let bit = 1 << pin;
+            match (self.dir & bit != 0, self.pins() & bit != 0) {
+                (true, true) => '*',
+                (true, false) => '.',
+                (false, true) => '1',
+                (false, false) => '0',
+            }
+        }).collect();
+        format!("GPIO [{}] out={:#06x} in={:#06x}", pins, self.outputs(), self.pins() & !self.dir)
+    }
+
+    fn update_irq(&self) {
+        self.irq.set((self.irq_status & self.irq_en) != 0);
+    }
+}
+
+/// Input changes applied at given instruction counts, loaded from file with `<instructions> <pin> <0|1>` lines
+pub struct GpioScript {
+    events: VecDeque<(u64, u8, bool)>,
+}
+
+impl GpioScript {
+    pub fn load(path: &Path) -> io::Result<GpioScript> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+
+        let mut events = Vec::new();
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let event = match fields[..] {
+                [at, pin, level] => at.parse::<u64>().ok().zip(parse_pin(pin)).zip(parse_level(level)),
+                _ => None,
+            };
+            let Some(((at, pin), level)) = event else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("GPIO script line {}: expected `instructions pin level`", lineno+1)));
+            };
+            events.push((at, pin, level));
+        }
+        events.sort_by_key(|e| e.0); // stable, events at the same time keep file order
+        Ok(GpioScript { events: events.into() })
+    }
+
+    /// Apply events scheduled up to current instruction count
+    pub fn apply(&mut self, instructions: u64, gpio: &mut Gpio) {
+        while let Some((_, pin, level)) = self.events.front().filter(|e| e.0 <= instructions).copied() {
+            self.events.pop_front();
+            gpio.set_input(pin, level);
+        }
+    }
+}
+
+pub fn parse_pin(s: &str) -> Option<u8> {
+    parse_addr(s).filter(|pin| *pin < GPIO_PINS as u32).map(|pin| pin as u8)
+}
+
+pub fn parse_level(s: &str) -> Option<bool> {
+    match s {
+        "0" | "low" => Some(false),
+        "1" | "high" => Some(true),
+        _ => None,
+    }
+}
@@ -0,0 +1,284 @@
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::UNIX_EPOCH;
+
+use super::bus::{Bus, BusMaster, Device};
+use super::irqc::IrqLine;
+use crate::support::sandbox::Sandbox;
+
+// Paravirtual host filesystem, gives guest drivers access to files in sandboxed host directory
+// (--host-dir) without rebuilding the SD image. Guest puts request data (path or file data) in a
+// shared RAM buffer at BUF (bus byte address, as DMA addresses) of CAP bytes, sets LEN, HANDLE and
+// ARG and writes command to CMD. Command runs as bus master at the end of the instruction, result
+// code is in STATUS and LEN holds number of bytes written back to the buffer (READ, STAT, LIST) or
+// consumed from it (WRITE). Results that don't fit in CAP bytes fail with STATUS_INVALID, buffer
+// at unmapped address or hostfs registers fails with STATUS_BUS_ERR.
+//
+// Commands:
+//   OPEN   path in buffer, ARG - OPEN_* flags, returns HANDLE
+//   CLOSE  HANDLE
+//   READ   up to min(LEN, CAP) bytes of HANDLE at current position to buffer, LEN 0 at end of file
+//   WRITE  LEN bytes from buffer to HANDLE at current position
+//   SEEK   set position of HANDLE to ARG
+//   STAT   path in buffer, buffer gets stat record (words: size lo, size hi, type, mtime lo, mtime hi)
+//   LIST   directory path in buffer, buffer gets stat record and name of ARG-th entry (sorted by
+//          name), STATUS_END past last entry
+// Host files are not part of --record logs, same as sim control files.
+
+pub struct HostFs {
+    sandbox: Option<Sandbox>,
+    files: [Option<File>; FILE_HANDLES],
+
+    cmd: u16,
+    status: u16,
+    ctrl: u16,
+    buf: u32,
+    cap: u16,
+    len: u16,
+    handle: u16,
+    arg: u32,
+    irq: IrqLine,
+}
+
+const CMD_ADDR: u32 = 0x0;
+const STATUS_ADDR: u32 = 0x1;
+const CTRL_ADDR: u32 = 0x2;
+const BUF_LO_ADDR: u32 = 0x3;
+const BUF_HI_ADDR: u32 = 0x4;
+const LEN_ADDR: u32 = 0x5;
+const HANDLE_ADDR: u32 = 0x6;
+const ARG_LO_ADDR: u32 = 0x7;
+const ARG_HI_ADDR: u32 = 0x8;
+const CAP_ADDR: u32 = 0x9; // buffer capacity in bytes
+
+const CMD_OPEN: u16 = 1;
+const CMD_CLOSE: u16 = 2;
+const CMD_READ: u16 = 3;
+const CMD_WRITE: u16 = 4;
+const CMD_SEEK: u16 = 5;
+const CMD_STAT: u16 = 6;
+const CMD_LIST: u16 = 7;
+
+const OPEN_READ: u32 = 0b00001;
+const OPEN_WRITE: u32 = 0b00010;
+const OPEN_CREATE: u32 = 0b00100;
+const OPEN_TRUNCATE: u32 = 0b01000;
+const OPEN_APPEND: u32 = 0b10000;
+
+const STATUS_OK: u16 = 0;
+const STATUS_NOT_FOUND: u16 = 1;
+const STATUS_DENIED: u16 = 2; // path outside of sandbox, no --host-dir or host permission error
+const STATUS_BAD_HANDLE: u16 = 3;
+const STATUS_IO: u16 = 4;
+const STATUS_INVALID: u16 = 5; // unknown command, bad flags or path
+const STATUS_END: u16 = 6;
+const STATUS_NO_HANDLES: u16 = 7;
+const STATUS_BUS_ERR: u16 = 8;
+
+const CTRL_BUSY: u16 = 0b001; // read only, command is pending
+const CTRL_IRQ_EN: u16 = 0b010;
+const CTRL_DONE: u16 = 0b100; // write 1 to clear
+
+const TYPE_FILE: u16 = 1;
+const TYPE_DIR: u16 = 2;
+
+const STAT_BYTES: usize = 10;
+const FILE_HANDLES: usize = 8;
+const ADDR_MASK: u32 = 0x1ff_ffff;
+
+impl Device for HostFs {
+    fn read(&mut self, addr: u32, _sel: u8) -> u16 {
+        match addr {
+            CMD_ADDR => self.cmd,
+            STATUS_ADDR => self.status,
+            CTRL_ADDR => self.ctrl,
+            BUF_LO_ADDR => self.buf as u16,
+            BUF_HI_ADDR => (self.buf >> 16) as u16,
+            LEN_ADDR => self.len,
+            HANDLE_ADDR => self.handle,
+            ARG_LO_ADDR => self.arg as u16,
+            ARG_HI_ADDR => (self.arg >> 16) as u16,
+            CAP_ADDR => self.cap,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u32, _sel: u8, data: u16) {
+        match addr {
+            CMD_ADDR => {
+                self.cmd = data;
+                self.ctrl |= CTRL_BUSY;
+            },
+            CTRL_ADDR => {
+                let done = self.ctrl & !data & CTRL_DONE;
+                self.ctrl = (self.ctrl & CTRL_BUSY) | (data & CTRL_IRQ_EN) | done;
+                self.update_irq();
+            },
+            BUF_LO_ADDR => { self.buf = (self.buf & 0xffff_0000) | data as u32; },
+            BUF_HI_ADDR => { self.buf = ((self.buf & 0xffff) | ((data as u32) << 16)) & ADDR_MASK; },
+            LEN_ADDR => { self.len = data; },
+            HANDLE_ADDR => { self.handle = data; },
+            ARG_LO_ADDR => { self.arg = (self.arg & 0xffff_0000) | data as u32; },
+            ARG_HI_ADDR => { self.arg = (self.arg & 0xffff) | ((data as u32) << 16); },
+            CAP_ADDR => { self.cap = data; },
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        // guest driver state is gone, so are its handles
+        self.files = Default::default();
+        self.cmd = 0;
+        self.status = STATUS_OK;
+        self.ctrl = 0;
+        self.buf = 0;
+        self.cap = 0;
+        self.len = 0;
+        self.handle = 0;
+        self.arg = 0;
+        self.update_irq();
+    }
+}
+
+impl BusMaster for HostFs {
+    fn master_tick(&mut self, bus: &mut Bus) {
+        if self.ctrl & CTRL_BUSY == 0 {
+            return;
+        }
+        self.status = match self.command(bus) {
+            Ok(()) => STATUS_OK,
+            Err(status) => status,
+        };
+        self.ctrl = (self.ctrl & !CTRL_BUSY) | CTRL_DONE;
+        self.update_irq();
+    }
+}
+
+impl HostFs {
+    pub fn new(sandbox: Option<Sandbox>, irq: IrqLine) -> HostFs {
+        HostFs {
+            sandbox, files: Default::default(),
+            cmd: 0, status: STATUS_OK, ctrl: 0, buf: 0, cap: 0, len: 0, handle: 0, arg: 0, irq,
+        }
+    }
+
+    fn command(&mut self, bus: &mut Bus) -> Result<(), u16> {
+        match self.cmd {
+            CMD_OPEN => {
+                let path = self.path(bus)?;
+                let flags = self.arg;
+                if flags & (OPEN_READ | OPEN_WRITE | OPEN_APPEND) == 0 {
+                    return Err(STATUS_INVALID);
+                }
+                let slot = self.files.iter().position(|f| f.is_none()).ok_or(STATUS_NO_HANDLES)?;
+                let file = File::options()
+                    .read(flags & OPEN_READ != 0)
+                    .write(flags & OPEN_WRITE != 0)
+                    .append(flags & OPEN_APPEND != 0)
+                    .create(flags & OPEN_CREATE != 0)
+                    .truncate(flags & OPEN_TRUNCATE != 0)
+                    .open(path).map_err(io_status)?;
+                self.files[slot] = Some(file);
+                self.handle = slot as u16;
+            },
+            CMD_CLOSE => {
+                self.file()?;
+                self.files[self.handle as usize] = None;
+            },
+            CMD_READ => {
+                let mut data = vec![0; self.len.min(self.cap) as usize];
+                self.check_buf(bus, data.len())?;
+                let count = self.file()?.read(&mut data).map_err(io_status)?;
+                self.write_buf(bus, &data[..count])?;
+            },
+            CMD_WRITE => {
+                let data = self.read_buf(bus)?;
+                self.file()?.write_all(&data).map_err(io_status)?;
+            },
+            CMD_SEEK => {
+                let offset = self.arg as u64;
+                self.file()?.seek(SeekFrom::Start(offset)).map_err(io_status)?;
+            },
+            CMD_STAT => {
+                let meta = fs::metadata(self.path(bus)?).map_err(io_status)?;
+                self.write_buf(bus, &stat_record(&meta))?;
+            },
+            CMD_LIST => {
+                let mut entries = fs::read_dir(self.path(bus)?).map_err(io_status)?
+                    .collect::<io::Result<Vec<_>>>().map_err(io_status)?;
+                entries.sort_by_key(|e| e.file_name());
+                let entry = entries.get(self.arg as usize).ok_or(STATUS_END)?;
+                let mut data = stat_record(&entry.metadata().map_err(io_status)?).to_vec();
+                data.extend_from_slice(entry.file_name().as_encoded_bytes());
+                self.write_buf(bus, &data)?;
+            },
+            _ => return Err(STATUS_INVALID),
+        }
+        Ok(())
+    }
+
+    fn file(&mut self) -> Result<&mut File, u16> {
+        self.files.get_mut(self.handle as usize).and_then(|f| f.as_mut()).ok_or(STATUS_BAD_HANDLE)
+    }
+
+    fn path(&self, bus: &mut Bus) -> Result<std::path::PathBuf, u16> {
+        let name = String::from_utf8(self.read_buf(bus)?).map_err(|_| STATUS_INVALID)?;
+        self.sandbox.as_ref().and_then(|sandbox| sandbox.resolve(&name)).ok_or(STATUS_DENIED)
+    }
+
+    /// Buffer bytes [0, len) are within CAP and accessible on the bus
+    fn check_buf(&self, bus: &Bus, len: usize) -> Result<(), u16> {
+        if len > self.cap as usize {
+            return Err(STATUS_INVALID);
+        }
+        let accessible = (0..len as u32).all(|i| bus.can_access(((self.buf + i) & ADDR_MASK) >> 1));
+        if accessible { Ok(()) } else { Err(STATUS_BUS_ERR) }
+    }
+
+    /// LEN bytes of shared buffer
+    fn read_buf(&self, bus: &mut Bus) -> Result<Vec<u8>, u16> {
+        self.check_buf(bus, self.len as usize)?;
+        Ok((0..self.len as u32).map(|i| {
+            let addr = (self.buf + i) & ADDR_MASK;
+            (bus.read(addr >> 1, 0b11) >> ((addr & 1) * 8)) as u8
+        }).collect())
+    }
+
+    /// Copy data to shared buffer and set LEN to its length
+    fn write_buf(&mut self, bus: &mut Bus, data: &[u8]) -> Result<(), u16> {
+        self.check_buf(bus, data.len())?;
+        for (i, byte) in data.iter().enumerate() {
+            let addr = (self.buf + i as u32) & ADDR_MASK;
+            bus.write(addr >> 1, 0b01 << (addr & 1), *byte as u16);
+        }
+        self.len = data.len() as u16;
+        Ok(())
+    }
+
+    fn update_irq(&self) {
+        self.irq.set((self.ctrl & CTRL_IRQ_EN) != 0 && (self.ctrl & CTRL_DONE) != 0);
+    }
+}
+
+fn stat_record(meta: &Metadata) -> [u8; STAT_BYTES] {
+    let size = meta.len().min(u32::MAX as u64) as u32;
+    let kind = if meta.is_dir() { TYPE_DIR } else { TYPE_FILE };
+    let mtime = meta.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs() as u32);
+
+    let mut record = [0; STAT_BYTES];
+    record[0..4].copy_from_slice(&size.to_le_bytes());
+    record[4..6].copy_from_slice(&kind.to_le_bytes());
+    record[6..10].copy_from_slice(&mtime.to_le_bytes());
+    record
+}
+
+fn io_status(e: io::Error) -> u16 {
+    match e.kind() {
+        io::ErrorKind::NotFound => STATUS_NOT_FOUND,
+        io::ErrorKind::PermissionDenied => STATUS_DENIED,
+        io::ErrorKind::InvalidInput => STATUS_INVALID,
+        _ => STATUS_IO,
+    }
+}
@@ -1,25 +1,46 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+
 use crate::devices::bus::Device;
 
+// Interrupt line numbers of devices (bit index in irq_active)
+pub const IRQ_UART: u8 = 0;
+pub const IRQ_TIMER: u8 = 1;
+pub const IRQ_SPI: u8 = 2;
+pub const IRQ_GPIO: u8 = 3;
+pub const IRQ_KEYBOARD: u8 = 4;
+pub const IRQ_RTC: u8 = 5;
+pub const IRQ_DMA: u8 = 6;
+pub const IRQ_WATCHDOG: u8 = 7;
+pub const IRQ_HOSTFS: u8 = 8;
+
 pub struct Irqc {
     irq_mask: u16,
-    irq_active: u16
+    irq_active: u16, // latched on rising edge, cleared by guest
+    irq_level: u16,  // current state of lines
 }
 
 impl Device for Irqc {
     fn read(&mut self, addr: u32, _sel: u8) -> u16 {
         match addr {
             0b10 => self.irq_mask,
-            _ => self.irq_mask & self.irq_active,
+            _ => self.irq_mask & self.pending(),
         }
     }
 
     fn write(&mut self, addr: u32, _sel: u8, data: u16) {
         match addr {
-            0b1 => { self.irq_active = self.irq_active & !data; },
+            0b1 => { self.irq_active &= !data; },
             0b10 => { self.irq_mask = data },
             _ => {},
         };
     }
+
+    fn reset(&mut self) {
+        // line levels are kept, they follow devices
+        self.irq_mask = 0;
+        self.irq_active = 0;
+    }
 }
 
 impl Irqc {
@@ -27,11 +48,45 @@ impl Irqc {
         self.irq_active |= code;
     }
 
+    pub fn set_line(&mut self, line: u8, level: bool) {
+        let bit = 1 << line;
+        if level && (self.irq_level & bit) == 0 {
+            self.trigger(bit);
+        }
+        if level {
+            self.irq_level |= bit;
+        } else {
+            self.irq_level &= !bit;
+        }
+    }
+
+    // line held high stays pending after guest clears it
+    fn pending(&self) -> u16 {
+        self.irq_active | self.irq_level
+    }
+
     pub fn active(&self) -> bool {
-        (self.irq_active & self.irq_mask) != 0
+        (self.pending() & self.irq_mask) != 0
     }
 
     pub fn new() -> Irqc {
-        Irqc { irq_mask: 0, irq_active: 0 }
+        Irqc { irq_mask: 0, irq_active: 0, irq_level: 0 }
+    }
+}
+
+/// Interrupt line of a device, connected to numbered input of Irqc
+pub struct IrqLine {
+    irqc: Rc<RefCell<Irqc>>,
+    line: u8,
+}
+
+impl IrqLine {
+    pub fn new(irqc: &Rc<RefCell<Irqc>>, line: u8) -> IrqLine {
+        IrqLine { irqc: Rc::clone(irqc), line }
+    }
+
+    /// Raise (true) or lower (false) the line. Rising edge latches interrupt in Irqc.
+    pub fn set(&self, level: bool) {
+        self.irqc.borrow_mut().set_line(self.line, level);
     }
 }
@@ -0,0 +1,320 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::rc::Rc;
+
+use super::bus::Device;
+use super::irqc::IrqLine;
+use crate::debug::symbols::parse_addr;
+use crate::support::text::parse_text;
+use crate::support::replay::{InputLog, InputEvent};
+
+// PS/2 keyboard controller delivering scan code set 2 bytes (0xf0 prefix for break codes, 0xe0
+// for extended keys). Host keys come from terminal window (characters are translated to key presses
+// and releases, with shift/ctrl where needed), from script or from the monitor.
+
+pub struct Keyboard {
+    fifo: VecDeque<u8>,
+    log: Rc<RefCell<InputLog>>,
+    ctrl: u16,
+    irq: IrqLine,
+
+    term_escape: Vec<u8>,
+    escape_wait: u32,
+}
+
+const DATA_ADDR: u32 = 0x0; // read pops scan code byte
+const STATUS_ADDR: u32 = 0x1;
+const CTRL_ADDR: u32 = 0x2;
+
+const STATUS_DATA_READY: u16 = 0b1;
+const CTRL_IRQ_EN: u16 = 0b1;
+
+const PREFIX_EXTENDED: u8 = 0xe0;
+const PREFIX_BREAK: u8 = 0xf0;
+
+const ESCAPE_TIMEOUT: u32 = 10_000; // instructions to wait for rest of terminal escape sequence
+
+impl Device for Keyboard {
+    fn read(&mut self, addr: u32, _sel: u8) -> u16 {
+        match addr {
+            DATA_ADDR => {
+                let data = self.fifo.pop_front().unwrap_or(0);
+                self.update_irq();
+                data as u16
+            },
+            STATUS_ADDR => if self.fifo.is_empty() { 0 } else { STATUS_DATA_READY },
+            CTRL_ADDR => self.ctrl,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u32, _sel: u8, data: u16) {
+        if addr == CTRL_ADDR {
+            self.ctrl = data & CTRL_IRQ_EN;
+            self.update_irq();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.fifo.clear();
+        self.ctrl = 0;
+        self.update_irq();
+    }
+}
+
+impl Keyboard {
+    pub fn new(log: Rc<RefCell<InputLog>>, irq: IrqLine) -> Keyboard {
+        Keyboard { fifo: VecDeque::new(), log, ctrl: 0, irq, term_escape: Vec::new(), escape_wait: 0 }
+    }
+
+    /// Translate keys typed in terminal window
+    pub fn term_received(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.term_input(byte);
+        }
+    }
+
+    /// Flush incomplete terminal escape sequence after timeout, called between instructions
+    pub fn poll_term(&mut self) {
+        if !self.term_escape.is_empty() {
+            self.escape_wait += 1;
+            if self.escape_wait > ESCAPE_TIMEOUT {
+                // lone escape key, or sequence that never completed
+                for byte in std::mem::take(&mut self.term_escape) {
+                    self.type_char(byte as char);
+                }
+            }
+        }
+    }
+
+    /// Queue scan code bytes, every host key event ends up here
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.log.borrow_mut().input(&InputEvent::Keyboard(bytes.to_vec()));
+        self.fifo.extend(bytes);
+        self.update_irq();
+    }
+
+    pub fn press(&mut self, key: Key) {
+        match key {
+            Key::Normal(code) => self.push(&[code]),
+            Key::Extended(code) => self.push(&[PREFIX_EXTENDED, code]),
+        }
+    }
+
+    pub fn release(&mut self, key: Key) {
+        match key {
+            Key::Normal(code) => self.push(&[PREFIX_BREAK, code]),
+            Key::Extended(code) => self.push(&[PREFIX_EXTENDED, PREFIX_BREAK, code]),
+        }
+    }
+
+    pub fn tap(&mut self, key: Key) {
+        self.press(key);
+        self.release(key);
+    }
+
+    /// Type character with modifiers needed to produce it, unknown characters are ignored
+    pub fn type_char(&mut self, c: char) {
+        let Some((modifier, key)) = char_key(c) else {
+            return;
+        };
+        if let Some(modifier) = modifier {
+            self.press(modifier);
+        }
+        self.tap(key);
+        if let Some(modifier) = modifier {
+            self.release(modifier);
+        }
+    }
+
+    pub fn type_text(&mut self, text: &str) {
+        for c in text.chars() {
+            self.type_char(c);
+        }
+    }
+
+    // terminal sends cursor keys as escape sequences (`ESC [ A`, `ESC [ 3 ~`)
+    fn term_input(&mut self, byte: u8) {
+        if self.term_escape.is_empty() && byte != 0x1b {
+            self.type_char(byte as char);
+            return;
+        }
+
+        self.term_escape.push(byte);
+        self.escape_wait = 0;
+        let key = match &self.term_escape[..] {
+            [0x1b] | [0x1b, b'['] | [0x1b, b'[', b'0'..=b'9'] => return, // incomplete
+            [0x1b, b'[', b'A'] => key_by_name("up"),
+            [0x1b, b'[', b'B'] => key_by_name("down"),
+            [0x1b, b'[', b'C'] => key_by_name("right"),
+            [0x1b, b'[', b'D'] => key_by_name("left"),
+            [0x1b, b'[', b'H'] => key_by_name("home"),
+            [0x1b, b'[', b'F'] => key_by_name("end"),
+            [0x1b, b'[', b'2', b'~'] => key_by_name("insert"),
+            [0x1b, b'[', b'3', b'~'] => key_by_name("delete"),
+            [0x1b, b'[', b'5', b'~'] => key_by_name("pageup"),
+            [0x1b, b'[', b'6', b'~'] => key_by_name("pagedown"),
+            _ => None,
+        };
+
+        let sequence = std::mem::take(&mut self.term_escape);
+        match key {
+            Some(key) => self.tap(key),
+            None => {
+                // not a known sequence, pass bytes as typed
+                for byte in sequence {
+                    self.type_char(byte as char);
+                }
+            },
+        }
+    }
+
+    fn update_irq(&self) {
+        self.irq.set((self.ctrl & CTRL_IRQ_EN) != 0 && !self.fifo.is_empty());
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Key {
+    Normal(u8),
+    Extended(u8),
+}
+
+const KEY_NAMES: [(&str, Key); 47] = [
+    ("enter", Key::Normal(0x5a)), ("esc", Key::Normal(0x76)), ("space", Key::Normal(0x29)),
+    ("backspace", Key::Normal(0x66)), ("tab", Key::Normal(0x0d)), ("capslock", Key::Normal(0x58)),
+    ("lshift", Key::Normal(0x12)), ("rshift", Key::Normal(0x59)), ("lctrl", Key::Normal(0x14)),
+    ("lalt", Key::Normal(0x11)), ("rctrl", Key::Extended(0x14)), ("ralt", Key::Extended(0x11)),
+    ("f1", Key::Normal(0x05)), ("f2", Key::Normal(0x06)), ("f3", Key::Normal(0x04)), ("f4", Key::Normal(0x0c)),
+    ("f5", Key::Normal(0x03)), ("f6", Key::Normal(0x0b)), ("f7", Key::Normal(0x83)), ("f8", Key::Normal(0x0a)),
+    ("f9", Key::Normal(0x01)), ("f10", Key::Normal(0x09)), ("f11", Key::Normal(0x78)), ("f12", Key::Normal(0x07)),
+    ("up", Key::Extended(0x75)), ("down", Key::Extended(0x72)), ("left", Key::Extended(0x6b)), ("right", Key::Extended(0x74)),
+    ("home", Key::Extended(0x6c)), ("end", Key::Extended(0x69)), ("insert", Key::Extended(0x70)), ("delete", Key::Extended(0x71)),
+    ("pageup", Key::Extended(0x7d)), ("pagedown", Key::Extended(0x7a)),
+    ("`", Key::Normal(0x0e)), ("-", Key::Normal(0x4e)), ("=", Key::Normal(0x55)), ("[", Key::Normal(0x54)),
+    ("]", Key::Normal(0x5b)), ("\\", Key::Normal(0x5d)), (";", Key::Normal(0x4c)), ("'", Key::Normal(0x52)),
+    (",", Key::Normal(0x41)), (".", Key::Normal(0x49)), ("/", Key::Normal(0x4a)),
+    ("lgui", Key::Extended(0x1f)), ("rgui", Key::Extended(0x27)),
+];
+
+const LETTER_CODES: [u8; 26] = [
+    0x1c, 0x32, 0x21, 0x23, 0x24, 0x2b, 0x34, 0x33, 0x43, 0x3b, 0x42, 0x4b, 0x3a,
+    0x31, 0x44, 0x4d, 0x15, 0x2d, 0x1b, 0x2c, 0x3c, 0x2a, 0x1d, 0x22, 0x35, 0x1a,
+];
+const DIGIT_CODES: [u8; 10] = [0x45, 0x16, 0x1e, 0x26, 0x25, 0x2e, 0x36, 0x3d, 0x3e, 0x46];
+
+// US layout, characters typed with shift and their unshifted key
+const SHIFTED: [(char, char); 21] = [
+    ('~', '`'), ('!', '1'), ('@', '2'), ('#', '3'), ('$', '4'), ('%', '5'), ('^', '6'), ('&', '7'),
+    ('*', '8'), ('(', '9'), (')', '0'), ('_', '-'), ('+', '='), ('{', '['), ('}', ']'), ('|', '\\'),
+    (':', ';'), ('"', '\''), ('<', ','), ('>', '.'), ('?', '/'),
+];
+
+/// Key by name (`enter`, `f1`, `up`, ...) or by single unshifted character (`a`, `7`, `/`)
+pub fn key_by_name(name: &str) -> Option<Key> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        if c.is_ascii_lowercase() {
+            return Some(Key::Normal(LETTER_CODES[(c as u8 - b'a') as usize]));
+        }
+        if c.is_ascii_digit() {
+            return Some(Key::Normal(DIGIT_CODES[(c as u8 - b'0') as usize]));
+        }
+    }
+    KEY_NAMES.iter().find(|(key_name, _)| *key_name == name).map(|(_, key)| *key)
+}
+
+/// Modifier and key producing character
+fn char_key(c: char) -> Option<(Option<Key>, Key)> {
+    let shift = key_by_name("lshift");
+    let ctrl = key_by_name("lctrl");
+    match c {
+        '\n' | '\r' => Some((None, key_by_name("enter")?)),
+        '\t' => Some((None, key_by_name("tab")?)),
+        '\x08' | '\x7f' => Some((None, key_by_name("backspace")?)),
+        '\x1b' => Some((None, key_by_name("esc")?)),
+        ' ' => Some((None, key_by_name("space")?)),
+        '\x01'..='\x1a' => Some((ctrl, key_by_name(&((c as u8 - 1 + b'a') as char).to_string())?)),
+        'A'..='Z' => Some((shift, key_by_name(&c.to_ascii_lowercase().to_string())?)),
+        _ => match SHIFTED.iter().find(|(shifted, _)| *shifted == c) {
+            Some((_, base)) => Some((shift, key_by_name(&base.to_string())?)),
+            None => Some((None, key_by_name(&c.to_string())?)),
+        },
+    }
+}
+
+/// Key events applied at given instruction counts. Lines have form `<instructions> <action>`, where
+/// action is `type "text"` (with \n, \t, \e, \\ and \" escapes), `press <key>`, `release <key>`, `tap <key>`
+/// or `raw <byte>...`
+pub struct KeyboardScript {
+    events: VecDeque<(u64, KeyEvent)>,
+}
+
+#[derive(Debug)]
+pub enum KeyEvent {
+    Type(String),
+    Press(Key),
+    Release(Key),
+    Tap(Key),
+    Raw(Vec<u8>),
+}
+
+impl KeyEvent {
+    pub fn parse(action: &str) -> Result<KeyEvent, String> {
+        let (command, arg) = action.trim().split_once(char::is_whitespace).unwrap_or((action.trim(), ""));
+        let arg = arg.trim();
+        let key = || key_by_name(arg).ok_or_else(|| format!("unknown key `{}`", arg));
+        match command {
+            "type" => Ok(KeyEvent::Type(parse_text(arg)?)),
+            "press" => Ok(KeyEvent::Press(key()?)),
+            "release" => Ok(KeyEvent::Release(key()?)),
+            "tap" => Ok(KeyEvent::Tap(key()?)),
+            "raw" => arg.split_whitespace()
+                .map(|b| parse_addr(b).and_then(|b| u8::try_from(b).ok()).ok_or_else(|| format!("invalid byte `{}`", b)))
+                .collect::<Result<_, _>>().map(KeyEvent::Raw),
+            _ => Err(format!("unknown action `{}`", command)),
+        }
+    }
+
+    pub fn apply(&self, keyboard: &mut Keyboard) {
+        match self {
+            KeyEvent::Type(text) => keyboard.type_text(text),
+            KeyEvent::Press(key) => keyboard.press(*key),
+            KeyEvent::Release(key) => keyboard.release(*key),
+            KeyEvent::Tap(key) => keyboard.tap(*key),
+            KeyEvent::Raw(bytes) => keyboard.push(bytes),
+        }
+    }
+}
+
+impl KeyboardScript {
+    pub fn load(path: &Path) -> io::Result<KeyboardScript> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+
+        let mut events = Vec::new();
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |msg: String| io::Error::new(io::ErrorKind::InvalidData, format!("keyboard script line {}: {}", lineno+1, msg));
+            let (at, action) = line.split_once(char::is_whitespace).ok_or_else(|| error("expected `instructions action`".to_string()))?;
+            let at = at.parse::<u64>().map_err(|_| error(format!("invalid instruction count `{}`", at)))?;
+            events.push((at, KeyEvent::parse(action).map_err(error)?));
+        }
+        events.sort_by_key(|e| e.0);
+        Ok(KeyboardScript { events: events.into() })
+    }
+
+    /// Apply events scheduled up to current instruction count
+    pub fn apply(&mut self, instructions: u64, keyboard: &mut Keyboard) {
+        while self.events.front().is_some_and(|e| e.0 <= instructions) {
+            let (_, event) = self.events.pop_front().unwrap();
+            event.apply(keyboard);
+        }
+    }
+}
@@ -6,4 +6,17 @@ pub mod rom;
 
 pub mod irqc;
 pub mod timer;
+pub mod spi;
+pub mod disk;
 pub mod sd;
+pub mod spi_flash;
+pub mod gpio;
+pub mod font;
+pub mod framebuffer;
+pub mod keyboard;
+pub mod rtc;
+pub mod dma;
+pub mod sim_control;
+pub mod watchdog;
+pub mod sysctrl;
+pub mod hostfs;
@@ -1,5 +1,6 @@
 use crate::devices::bus::Device;
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct RAM {
     mem: Box<[u16]>
 }
@@ -25,6 +26,14 @@ impl RAM {
         RAM { mem: vec![0; length].into_boxed_slice() }
     }
 
+    pub fn words(&self) -> &[u16] {
+        &self.mem
+    }
+
+    pub fn clear(&mut self) {
+        self.mem.fill(0);
+    }
+
     pub fn load_at(&mut self, addr: u32, data: &[u8]) {
         let mut le_data = vec![0; data.len()/2];
         for (pos, ent) in le_data.iter_mut().enumerate() {
@@ -33,7 +42,7 @@ impl RAM {
 
         self.mem[addr as usize..addr as usize+le_data.len()].copy_from_slice(&le_data);
 
-        if data.len() % 2 != 0 {
+        if !data.len().is_multiple_of(2) {
             self.mem[addr as usize + le_data.len()] &= 0xff00;
             self.mem[addr as usize + le_data.len()] |= *data.last().unwrap() as u16;
         }
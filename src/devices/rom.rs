@@ -1,5 +1,6 @@
 use crate::devices::bus::Device;
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct ROM<'a> {
     mem: &'a[u16] 
 }
@@ -14,7 +15,7 @@ impl Device for ROM<'_> {
 }
 
 impl ROM<'_> {
-    pub fn new(content: &[u16])  -> ROM {
+    pub fn new(content: &[u16])  -> ROM<'_> {
         ROM { mem: content }
     }
 }
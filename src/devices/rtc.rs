@@ -0,0 +1,137 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::bus::Device;
+use super::irqc::IrqLine;
+use crate::support::replay::{HostClock, InputLog};
+use crate::support::time::{civil_from_days, INSTRUCTIONS_PER_SECOND, SECS_PER_DAY};
+
+// Real-time clock counting unix seconds, with calendar view of current time and alarm interrupt.
+// Reading SECONDS_LO latches the whole time, so following reads of other time registers are
+// consistent. Writing SECONDS_LO sets the clock to SECONDS_HI:SECONDS_LO (write high part first).
+//
+// Time is taken from host clock, or in deterministic mode it starts at fixed epoch and advances
+// with executed instructions, so runs are reproducible.
+
+pub struct Rtc {
+    source: RtcSource,
+    instructions: u64,
+    offset: i64, // set by guest, relative to source time
+
+    latched: i64,
+    seconds_hi: u16,
+    alarm: u32,
+    ctrl: u16,
+
+    log: Rc<RefCell<InputLog>>,
+    irq: IrqLine,
+}
+
+pub enum RtcSource {
+    Host,
+    Deterministic { epoch: i64 },
+}
+
+const SECONDS_LO_ADDR: u32 = 0x0;
+const SECONDS_HI_ADDR: u32 = 0x1;
+const YEAR_ADDR: u32 = 0x2;
+const MONTH_DAY_ADDR: u32 = 0x3; // month << 8 | day
+const HOUR_MIN_ADDR: u32 = 0x4; // hour << 8 | minute
+const SEC_WEEKDAY_ADDR: u32 = 0x5; // weekday << 8 | second, weekday 0 is Sunday
+const ALARM_LO_ADDR: u32 = 0x6;
+const ALARM_HI_ADDR: u32 = 0x7;
+const CTRL_ADDR: u32 = 0x8;
+
+const CTRL_ALARM_EN: u16 = 0b001;
+const CTRL_ALARM_IRQ_EN: u16 = 0b010;
+const CTRL_ALARM_FIRED: u16 = 0b100; // write 1 to clear
+
+const HOST_ALARM_CHECK_INSTRUCTIONS: u64 = 10_000;
+
+impl Device for Rtc {
+    fn read(&mut self, addr: u32, _sel: u8) -> u16 {
+        let days = self.latched.div_euclid(SECS_PER_DAY);
+        let day_secs = self.latched.rem_euclid(SECS_PER_DAY);
+        match addr {
+            SECONDS_LO_ADDR => {
+                self.latched = self.now();
+                self.latched as u16
+            },
+            SECONDS_HI_ADDR => (self.latched >> 16) as u16,
+            YEAR_ADDR => civil_from_days(days).0 as u16,
+            MONTH_DAY_ADDR => {
+                let (_, month, day) = civil_from_days(days);
+                ((month << 8) | day) as u16
+            },
+            HOUR_MIN_ADDR => (((day_secs / 3600) << 8) | ((day_secs / 60) % 60)) as u16,
+            SEC_WEEKDAY_ADDR => ((((days + 4).rem_euclid(7)) << 8) | (day_secs % 60)) as u16, // 1970-01-01 was Thursday
+            ALARM_LO_ADDR => self.alarm as u16,
+            ALARM_HI_ADDR => (self.alarm >> 16) as u16,
+            CTRL_ADDR => self.ctrl,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u32, _sel: u8, data: u16) {
+        match addr {
+            SECONDS_HI_ADDR => { self.seconds_hi = data; },
+            SECONDS_LO_ADDR => {
+                let time = ((self.seconds_hi as u32) << 16) | data as u32;
+                self.offset = time as i64 - self.source_time();
+            },
+            ALARM_LO_ADDR => { self.alarm = (self.alarm & 0xffff_0000) | data as u32; },
+            ALARM_HI_ADDR => { self.alarm = (self.alarm & 0xffff) | ((data as u32) << 16); },
+            CTRL_ADDR => {
+                let fired = self.ctrl & !data & CTRL_ALARM_FIRED;
+                self.ctrl = (data & (CTRL_ALARM_EN | CTRL_ALARM_IRQ_EN)) | fired;
+                self.update_irq();
+            },
+            _ => {},
+        }
+    }
+
+    fn tick(&mut self) {
+        self.instructions += 1;
+        // alarm has one second resolution, host clock is not sampled on every instruction
+        let check = match self.source {
+            RtcSource::Host => self.instructions.is_multiple_of(HOST_ALARM_CHECK_INSTRUCTIONS),
+            RtcSource::Deterministic { .. } => true,
+        };
+        if check && self.ctrl & CTRL_ALARM_EN != 0 && self.now() >= self.alarm as i64 {
+            // one-shot, guest re-arms by enabling it again
+            self.ctrl = (self.ctrl & !CTRL_ALARM_EN) | CTRL_ALARM_FIRED;
+            self.update_irq();
+        }
+    }
+
+    fn reset(&mut self) {
+        // time keeps running, only alarm is disabled
+        self.alarm = 0;
+        self.ctrl = 0;
+        self.update_irq();
+    }
+}
+
+impl Rtc {
+    pub fn new(source: RtcSource, log: Rc<RefCell<InputLog>>, irq: IrqLine) -> Rtc {
+        Rtc { source, instructions: 0, offset: 0, latched: 0, seconds_hi: 0, alarm: 0, ctrl: 0, log, irq }
+    }
+
+    fn source_time(&self) -> i64 {
+        match self.source {
+            RtcSource::Host => self.log.borrow_mut().host_time(HostClock::Rtc, || {
+                SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+            }),
+            RtcSource::Deterministic { epoch } => epoch + (self.instructions / INSTRUCTIONS_PER_SECOND) as i64,
+        }
+    }
+
+    fn now(&self) -> i64 {
+        self.source_time() + self.offset
+    }
+
+    fn update_irq(&self) {
+        self.irq.set((self.ctrl & CTRL_ALARM_FIRED) != 0 && (self.ctrl & CTRL_ALARM_IRQ_EN) != 0);
+    }
+}
@@ -1,15 +1,54 @@
-use std::{fs::File, collections::VecDeque, io::{Seek, Read}};
+use std::collections::VecDeque;
 
-use super::bus::Device;
+use super::disk::{DiskImage, BLOCK_SIZE};
+use super::spi::SpiSlave;
+use crate::support::crc::{crc7, crc16};
 
 pub struct SD {
-    file: File,
+    image: DiskImage,
 
     command_buf: [u8; 6],
     response: VecDeque<u8>,
-    curr_resp: u8
+
+    block_transfer: bool,
+    transfer_done: bool,
+
+    data_state: DataState,
+
+    blocks: u64,
+    idle: bool,
+    app_cmd: bool,
+    crc_enabled: bool,
+
+    fault: Option<FaultInjector>,
+}
+
+// Data phase that follows block read/write commands
+enum DataState {
+    Idle,
+    WriteToken { multi: bool, block: u32 },
+    WriteData { multi: bool, block: u32, data: Vec<u8> },
+    ReadStream { next_block: u32 },
 }
 
+const BUSY_BYTES: usize = 8; // card signals busy (0x00) after write for this many bytes
+
+const TOKEN_START_BLOCK: u8 = 0xfe;
+const TOKEN_START_MULTI_WRITE: u8 = 0xfc;
+const TOKEN_STOP_MULTI_WRITE: u8 = 0xfd;
+const DATA_RESP_ACCEPTED: u8 = 0x05;
+const DATA_RESP_CRC_ERR: u8 = 0x0b;
+const DATA_RESP_WRITE_ERR: u8 = 0x0d;
+
+const DATA_ERR_TOKEN_ERROR: u8 = 0x01;
+const DATA_ERR_TOKEN_OUT_OF_RANGE: u8 = 0x08;
+
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_CMD: u8 = 0x04;
+const R1_COM_CRC_ERR: u8 = 0x08;
+const R1_ADDRESS_ERR: u8 = 0x20; // block address out of card range
+const R1_PARAM_ERR: u8 = 0x40;
+
 #[allow(non_camel_case_types)]
 #[derive(enumn::N)]
 #[repr(u8)]
@@ -21,102 +60,322 @@ enum Commands {
     CMD0 = 0x40,
     CMD8 = 0x40 | 8,
     CMD17 = 0x40 | 17,
+    CMD18 = 0x40 | 18,
+    CMD12 = 0x40 | 12,
+    CMD24 = 0x40 | 24,
+    CMD25 = 0x40 | 25,
+    CMD59 = 0x40 | 59,
 }
 
 
 // Simple SD card SPI mode mock. It is not a full implementation, just to satisfy piOS interface.
-// TODO: Make SPI device emulator that only calls the device. For now emulate SPI dev too
+// Card uses block addressing (SDHC), written blocks go to the disk image (directly or
+// to its copy-on-write overlay).
+// As in real SPI mode cards, CRC checking is off after reset and can be enabled with CMD59.
+// Data blocks sent by the card always carry valid CRC16 (unless corrupted by fault injection).
 
-impl Device for SD {
-    fn read(&mut self, addr: u32, _sel: u8) -> u16 {
-        if addr != 1 {
-            return 0;
+impl SpiSlave for SD {
+    fn transfer(&mut self, mosi: u8) -> u8 {
+        crate::trace!("sd mosi {:#04x}", mosi);
+        let miso = self.response.pop_front().unwrap_or(0xff);
+        if self.block_transfer && self.response.is_empty() {
+            self.block_transfer = false;
+            self.transfer_done = true;
         }
-        dbg!(self.curr_resp);
-        self.curr_resp as u16
-    }
 
-    fn write(&mut self, addr: u32, _sel: u8, data: u16) {
-        if addr != 0 {
-            return;
+        // bytes of data block are not interpreted as commands
+        if self.receive_data(mosi) {
+            return miso;
         }
 
         self.command_buf.rotate_left(1);
-        self.command_buf[self.command_buf.len()-1] = data as u8;
-        
-        dbg!(data);
-        self.curr_resp = self.response.pop_front().unwrap_or(0xff);
+        self.command_buf[self.command_buf.len()-1] = mosi;
+
+        if let DataState::ReadStream { next_block } = self.data_state {
+            if self.response.is_empty() {
+                self.data_state = if self.queue_read_block(next_block) {
+                    DataState::ReadStream { next_block: next_block+1 }
+                } else {
+                    DataState::Idle
+                };
+            }
+        }
 
-        if self.command_buf[0] != 0xff {
+        if self.command_buf[0] & 0xc0 == 0x40 { // start and transmission bits
             self.process_cmd();
             
             // invalidate completed command
             self.command_buf = [0xff;6];
         }
+        miso
+    }
+
+    fn select(&mut self, selected: bool) {
+        if !selected { // deselect aborts data phase
+            self.data_state = DataState::Idle;
+            self.command_buf = [0xff;6];
+        }
+    }
+
+    fn take_transfer_done(&mut self) -> bool {
+        std::mem::replace(&mut self.transfer_done, false)
     }
 }
 
 impl SD {
-    pub fn new(f: File) -> SD {
-        SD {file: f, command_buf: [0xff;6], response: VecDeque::new(), curr_resp: 0xff }
+    pub fn new(image: DiskImage, fault: Option<FaultInjector>) -> SD {
+        let blocks = image.blocks();
+        SD {
+            image, command_buf: [0xff;6], response: VecDeque::new(),
+            block_transfer: false, transfer_done: false,
+            data_state: DataState::Idle, blocks, idle: true, app_cmd: false, crc_enabled: false,
+            fault,
+        }
+    }
+
+    fn receive_data(&mut self, byte: u8) -> bool {
+        match &mut self.data_state {
+            DataState::WriteToken { multi, block } => {
+                let (multi, block) = (*multi, *block);
+                match byte {
+                    TOKEN_START_BLOCK if !multi => {
+                        self.data_state = DataState::WriteData { multi, block, data: Vec::with_capacity(BLOCK_SIZE+2) };
+                    },
+                    TOKEN_START_MULTI_WRITE if multi => {
+                        self.data_state = DataState::WriteData { multi, block, data: Vec::with_capacity(BLOCK_SIZE+2) };
+                    },
+                    TOKEN_STOP_MULTI_WRITE if multi => {
+                        self.response.push_back(0xff);
+                        self.push_busy();
+                        self.data_state = DataState::Idle;
+                    },
+                    _ if byte & 0xc0 == 0x40 => { // command, driver abandoned the write
+                        self.data_state = DataState::Idle;
+                        return false;
+                    },
+                    _ => {}, // wait bytes before token
+                }
+                true
+            },
+            DataState::WriteData { multi, block, data } => {
+                data.push(byte);
+                if data.len() < BLOCK_SIZE+2 { // data + crc
+                    return true;
+                }
+                let (multi, block) = (*multi, *block);
+                let mut block_data = std::mem::take(data);
+                if let Some(fault) = &mut self.fault {
+                    fault.corrupt(&mut block_data);
+                }
+
+                let crc = u16::from_be_bytes([block_data[BLOCK_SIZE], block_data[BLOCK_SIZE+1]]);
+                let error = if self.crc_enabled && crc != crc16(&block_data[..BLOCK_SIZE]) {
+                    Some(DATA_RESP_CRC_ERR)
+                } else if !self.write_block(block, block_data[..BLOCK_SIZE].try_into().unwrap()) {
+                    Some(DATA_RESP_WRITE_ERR)
+                } else {
+                    None
+                };
+                if let Some(data_resp) = error {
+                    self.response.push_back(data_resp);
+                    self.push_busy();
+                    self.data_state = DataState::Idle;
+                    return true;
+                }
+
+                self.response.push_back(DATA_RESP_ACCEPTED);
+                self.push_busy();
+                self.block_transfer = true;
+
+                self.data_state = if multi {
+                    DataState::WriteToken { multi, block: block+1 }
+                } else {
+                    DataState::Idle
+                };
+                true
+            },
+            _ => false,
+        }
+    }
+
+    fn push_busy(&mut self) {
+        self.response.extend([0x00; BUSY_BYTES]);
+    }
+
+    /// Queue data block read response, or data error token if block can't be read
+    fn queue_read_block(&mut self, block: u32) -> bool {
+        self.response.push_back(0xff); // data wait
+        crate::trace!("sd read block {}", block);
+
+        if block as u64 >= self.blocks {
+            self.response.push_back(DATA_ERR_TOKEN_OUT_OF_RANGE);
+            return false;
+        }
+
+        let mut buff = [0_u8; BLOCK_SIZE];
+        if let Err(e) = self.image.read_block(block, &mut buff) {
+            eprintln!("SD image read error: {}", e);
+            self.response.push_back(DATA_ERR_TOKEN_ERROR);
+            return false;
+        }
+
+        let mut data = buff.to_vec();
+        data.extend(crc16(&buff).to_be_bytes());
+        if let Some(fault) = &mut self.fault {
+            fault.corrupt(&mut data);
+        }
+
+        self.response.push_back(TOKEN_START_BLOCK);
+        self.response.extend(data);
+        self.block_transfer = true;
+        true
+    }
+
+    fn write_block(&mut self, block: u32, data: &[u8; BLOCK_SIZE]) -> bool {
+        crate::trace!("sd write block {}", block);
+        if block as u64 >= self.blocks {
+            return false;
+        }
+        let write_res = self.image.write_block(block, data);
+        if let Err(e) = &write_res {
+            eprintln!("SD image write error: {}", e);
+        }
+        write_res.is_ok()
+    }
+
+    fn block_arg(&self) -> u32 {
+        u32::from_be_bytes(self.command_buf[1..5].try_into().unwrap())
+    }
+
+    fn command_crc_valid(&self) -> bool {
+        self.command_buf[5] == (crc7(&self.command_buf[..5]) << 1) | 1 // crc and end bit
+    }
+
+    fn r1(&self, flags: u8) -> u8 {
+        flags | if self.idle { R1_IDLE } else { 0 }
     }
 
     fn process_cmd(&mut self) {
-        match Commands::n(self.command_buf[0]) {
+        let app_cmd = std::mem::replace(&mut self.app_cmd, false);
+        let cmd = Commands::n(self.command_buf[0]);
+
+        if self.crc_enabled && !self.command_crc_valid() {
+            self.response.push_back(self.r1(R1_COM_CRC_ERR));
+            return;
+        }
+
+        // card accepts only initialization commands until ACMD41 completes
+        let init_cmd = matches!(cmd, Some(Commands::CMD0 | Commands::CMD8 | Commands::ACMD_PRE_CMD55
+                                           | Commands::ACMD41 | Commands::CMD58 | Commands::CMD16 | Commands::CMD59));
+        if self.idle && !init_cmd {
+            self.response.push_back(self.r1(R1_ILLEGAL_CMD));
+            return;
+        }
+
+        match cmd {
             Some(Commands::ACMD_PRE_CMD55) => {
-                self.response.push_back(0x1); // STATUS_IDLE
+                self.app_cmd = true;
+                self.response.push_back(self.r1(0));
             },
-            Some(Commands::ACMD41) => {
-                dbg!(self.command_buf);
-                if self.command_buf[1] == 0x40 { // ARG_HC
-                    self.response.push_back(0x0); // STATUS_NULL - initialized
+            Some(Commands::ACMD41) if app_cmd => {
+                crate::trace!("sd cmd {:02x?}", self.command_buf);
+                if self.command_buf[1] & 0x40 != 0 { // ARG_HC
+                    self.idle = false; // initialized
                 }
+                // host not supporting HC never finishes initialization
+                self.response.push_back(self.r1(0));
             },
             Some(Commands::CMD58) => {
-                self.response.push_back(0x0); // STATUS_NULL
-                self.response.push_back(0x80); // OCR_0_HC
+                self.response.push_back(self.r1(0));
+                self.response.push_back(if self.idle { 0x40 } else { 0xc0 }); // OCR_0_HC, power up status when ready
                 self.response.push_back(0x10); // OCR_1_3V3
                 self.response.push_back(0x0);
                 self.response.push_back(0x0);
             },
             Some(Commands::CMD16) => {
-                if self.command_buf[3] != 0x2 || self.command_buf[4] != 0x0 {
-                    panic!("Unsupported block size");
-                }
-                self.response.push_back(0x0); // STATUS_NULL
+                // block length is fixed for high capacity cards
+                let block_len = self.block_arg();
+                self.response.push_back(self.r1(if block_len != BLOCK_SIZE as u32 { R1_PARAM_ERR } else { 0 }));
+            },
+            Some(Commands::CMD59) => { // CRC_ON_OFF
+                self.crc_enabled = self.command_buf[4] & 1 != 0;
+                self.response.push_back(self.r1(0));
             },
             Some(Commands::CMD0) => {
-                self.response.push_back(0x1); // STATUS_IDLE
-                dbg!(self.command_buf);
+                self.idle = true;
+                self.crc_enabled = false;
+                self.data_state = DataState::Idle;
+                self.response.push_back(self.r1(0));
+                crate::trace!("sd cmd {:02x?}", self.command_buf);
             },
             Some(Commands::CMD8) => {
-                self.response.push_back(0x1); // STATUS_NULL
+                self.response.push_back(self.r1(0));
                 self.response.push_back(0x0);
                 self.response.push_back(0x0);
                 self.response.push_back(0x1); // 3_VOLTAGE
                 self.response.push_back(self.command_buf[4]); // check pattern
             },
+            Some(Commands::CMD17 | Commands::CMD18 | Commands::CMD24 | Commands::CMD25) if self.block_arg() as u64 >= self.blocks => {
+                self.response.push_back(self.r1(R1_ADDRESS_ERR));
+            },
             Some(Commands::CMD17) => { // The actual READ BLOCK command
-                self.response.push_back(0x0); // status
-                self.response.push_back(0xff); // data wait
-                self.response.push_back(0xfe); // data start token
-                
-                let page = u32::from_be_bytes(self.command_buf[1..5].try_into().unwrap());
-                let seek_res = self.file.seek(std::io::SeekFrom::Start(page as u64 *512));
-                dbg!(&seek_res);
-                dbg!(page);
-                let mut buff = [0 as u8; 512];
-                if seek_res.is_ok() {
-                    self.file.read(buff.as_mut_slice()).unwrap();
+                self.response.push_back(self.r1(0));
+                self.queue_read_block(self.block_arg());
+            },
+            Some(Commands::CMD18) => { // READ MULTIPLE BLOCK, blocks are streamed until CMD12, past the last one with error token
+                self.response.push_back(self.r1(0));
+                let block = self.block_arg();
+                if self.queue_read_block(block) {
+                    self.data_state = DataState::ReadStream { next_block: block+1 };
                 }
-                
-                self.response.extend(buff.as_slice());
+            },
+            Some(Commands::CMD12) => { // STOP TRANSMISSION
+                self.response.clear();
+                self.block_transfer = false;
+                self.data_state = DataState::Idle;
+                self.response.push_back(0xff); // stuff byte
+                self.response.push_back(self.r1(0));
+                self.push_busy();
+            },
+            Some(Commands::CMD24) | Some(Commands::CMD25) => { // WRITE BLOCK, WRITE MULTIPLE BLOCK (until stop token)
+                let block = self.block_arg();
+                self.response.push_back(self.r1(0));
+                self.data_state = DataState::WriteToken { multi: matches!(cmd, Some(Commands::CMD25)), block };
+            },
+            _ => {
+                crate::trace!("sd cmd {:02x?}", self.command_buf);
+                self.response.push_back(self.r1(R1_ILLEGAL_CMD));
+            }
+        }
+    }
+}
 
+/// Corrupts bytes of data blocks passing through the card with given probability, to exercise
+/// CRC error handling in the guest driver. Uses seeded xorshift generator, so runs are repeatable.
+pub struct FaultInjector {
+    rate: f64,
+    state: u64,
+}
 
-                self.response.push_back(0x0); // crc not implemented
-                self.response.push_back(0x0);
+impl FaultInjector {
+    pub fn new(rate: f64, seed: u64) -> FaultInjector {
+        FaultInjector { rate, state: seed.max(1) } // xorshift state can't be zero
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    fn corrupt(&mut self, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            if (self.next() as f64 / u64::MAX as f64) < self.rate {
+                let bit = self.next() % 8;
+                *byte ^= 1 << bit;
+                eprintln!("SD fault injection: flipped bit {} of block byte {}", bit, i);
             }
-            _ => panic!("Unsupported command")
         }
     }
 }
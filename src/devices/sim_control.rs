@@ -0,0 +1,155 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use super::bus::Device;
+use crate::support::sandbox::Sandbox;
+
+// Simulator control (semihosting) device. Lets guest end the run with exit code that becomes host
+// process exit status (codes above 119 become 119, higher statuses are used by simulator), print
+// to host stdout/stderr, read number of executed instructions and access files in sandboxed host
+// directory (--host-dir).
+//
+// Files: select handle with FILE_SEL, write path bytes to FILE_NAME, then write command to FILE_CMD.
+// Path buffer is cleared after each command. FILE_DATA reads or writes one byte of selected file,
+// reading past end returns 0 and sets STATUS_EOF.
+
+pub struct SimControl {
+    exit_code: Option<u16>,
+    instructions: u64,
+    latched: u64,
+
+    sandbox: Option<Sandbox>,
+    files: [Option<HostFile>; FILE_HANDLES],
+    file_sel: usize,
+    name: Vec<u8>,
+    status: [u16; FILE_HANDLES],
+}
+
+enum HostFile {
+    Read(BufReader<File>),
+    Write(BufWriter<File>),
+}
+
+const EXIT_ADDR: u32 = 0x0;
+const STDOUT_ADDR: u32 = 0x1;
+const STDERR_ADDR: u32 = 0x2;
+const INSTR_0_ADDR: u32 = 0x3; // reading latches whole count, bits 15:0
+const INSTR_1_ADDR: u32 = 0x4;
+const INSTR_2_ADDR: u32 = 0x5;
+const INSTR_3_ADDR: u32 = 0x6;
+const FILE_SEL_ADDR: u32 = 0x7;
+const FILE_NAME_ADDR: u32 = 0x8;
+const FILE_CMD_ADDR: u32 = 0x9;
+const FILE_STATUS_ADDR: u32 = 0xa;
+const FILE_DATA_ADDR: u32 = 0xb;
+
+const CMD_OPEN_READ: u16 = 1;
+const CMD_OPEN_WRITE: u16 = 2; // create or truncate
+const CMD_OPEN_APPEND: u16 = 3;
+const CMD_CLOSE: u16 = 4;
+
+const STATUS_OPEN: u16 = 0b001;
+const STATUS_ERROR: u16 = 0b010; // last command or access failed, cleared by next command
+const STATUS_EOF: u16 = 0b100;
+
+const FILE_HANDLES: usize = 8;
+
+impl Device for SimControl {
+    fn read(&mut self, addr: u32, _sel: u8) -> u16 {
+        match addr {
+            INSTR_0_ADDR => {
+                self.latched = self.instructions;
+                self.latched as u16
+            },
+            INSTR_1_ADDR => (self.latched >> 16) as u16,
+            INSTR_2_ADDR => (self.latched >> 32) as u16,
+            INSTR_3_ADDR => (self.latched >> 48) as u16,
+            FILE_SEL_ADDR => self.file_sel as u16,
+            FILE_STATUS_ADDR => self.status[self.file_sel],
+            FILE_DATA_ADDR => self.read_byte() as u16,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u32, _sel: u8, data: u16) {
+        match addr {
+            EXIT_ADDR => { self.exit_code = Some(data); },
+            // output closed by host (e.g. piped to head) doesn't end the run
+            STDOUT_ADDR => { let _ = io::stdout().write_all(&[data as u8]); },
+            STDERR_ADDR => { let _ = io::stderr().write_all(&[data as u8]); },
+            FILE_SEL_ADDR => { self.file_sel = data as usize % FILE_HANDLES; },
+            FILE_NAME_ADDR => { self.name.push(data as u8); },
+            FILE_CMD_ADDR => {
+                let ok = self.command(data).is_some();
+                self.name.clear();
+                let open = if self.files[self.file_sel].is_some() { STATUS_OPEN } else { 0 };
+                self.status[self.file_sel] = open | if ok { 0 } else { STATUS_ERROR };
+            },
+            FILE_DATA_ADDR => self.write_byte(data as u8),
+            _ => {},
+        }
+    }
+
+    fn tick(&mut self) {
+        self.instructions += 1;
+    }
+}
+
+impl SimControl {
+    pub fn new(sandbox: Option<Sandbox>) -> SimControl {
+        SimControl {
+            exit_code: None, instructions: 0, latched: 0,
+            sandbox, files: Default::default(), file_sel: 0, name: Vec::new(), status: [0; FILE_HANDLES],
+        }
+    }
+
+    /// Exit code written by guest, simulation should stop when set
+    pub fn exit_code(&self) -> Option<u16> {
+        self.exit_code
+    }
+
+    fn command(&mut self, cmd: u16) -> Option<()> {
+        if cmd == CMD_CLOSE {
+            return match self.files[self.file_sel].take() {
+                Some(HostFile::Write(mut writer)) => writer.flush().ok(),
+                Some(HostFile::Read(_)) => Some(()),
+                None => None,
+            };
+        }
+
+        let name = String::from_utf8(self.name.clone()).ok()?;
+        let path = self.sandbox.as_ref()?.resolve(&name)?;
+        let file = match cmd {
+            CMD_OPEN_READ => HostFile::Read(BufReader::new(File::open(path).ok()?)),
+            CMD_OPEN_WRITE => HostFile::Write(BufWriter::new(File::create(path).ok()?)),
+            CMD_OPEN_APPEND => HostFile::Write(BufWriter::new(File::options().append(true).create(true).open(path).ok()?)),
+            _ => return None,
+        };
+        self.files[self.file_sel] = Some(file);
+        Some(())
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let mut byte = [0];
+        let result = match &mut self.files[self.file_sel] {
+            Some(HostFile::Read(reader)) => reader.read(&mut byte).ok(),
+            _ => None,
+        };
+        match result {
+            Some(1) => {},
+            Some(_) => { self.status[self.file_sel] |= STATUS_EOF; },
+            None => { self.status[self.file_sel] |= STATUS_ERROR; },
+        }
+        byte[0]
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        let ok = match &mut self.files[self.file_sel] {
+            Some(HostFile::Write(writer)) => writer.write_all(&[byte]).is_ok(),
+            _ => false,
+        };
+        if !ok {
+            self.status[self.file_sel] |= STATUS_ERROR;
+        }
+    }
+}
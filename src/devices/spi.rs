@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+
+use super::bus::Device;
+use super::irqc::IrqLine;
+
+/// Device connected to SPI controller on its own chip select line
+pub trait SpiSlave {
+    /// Exchange one byte, returns byte shifted out by the device (full duplex)
+    fn transfer(&mut self, mosi: u8) -> u8;
+
+    /// Chip select line changed, devices framing commands with CS should reset their state
+    fn select(&mut self, _selected: bool) {}
+
+    /// Returns true (once) when device finished data transfer, used to signal interrupt
+    fn take_transfer_done(&mut self) -> bool {
+        false
+    }
+}
+
+// SPI controller with byte-wide data register and chip select register. Transfers complete
+// immediately after write to data register. Reading RX_CLOCK transfers 0xff and returns received
+// byte in single access (for DMA), other registers are read without side effects.
+
+pub struct SpiController {
+    slaves: BTreeMap<u16, Box<dyn SpiSlave>>,
+    cs: u16,
+
+    rx: u8,
+    ctrl: u16,
+    irq: IrqLine,
+}
+
+const DATA_ADDR: u32 = 0x0;
+const RX_ADDR: u32 = 0x1;
+const CTRL_ADDR: u32 = 0x2;
+const CS_ADDR: u32 = 0x3;
+const STATUS_ADDR: u32 = 0x4;
+const RX_CLOCK_ADDR: u32 = 0x5;
+
+const CTRL_IRQ_EN: u16 = 0b01;
+const CTRL_DONE: u16 = 0b10; // selected device finished data transfer, write 1 to clear
+
+const STATUS_PRESENT: u16 = 0b01; // device is connected at selected CS, write unused CS to deselect all
+
+impl Device for SpiController {
+    fn read(&mut self, addr: u32, _sel: u8) -> u16 {
+        match addr {
+            RX_CLOCK_ADDR => {
+                // clock out dummy byte and return received one in single access, used by DMA
+                self.transfer(0xff);
+                self.rx as u16
+            },
+            RX_ADDR => self.rx as u16,
+            CTRL_ADDR => self.ctrl,
+            CS_ADDR => self.cs,
+            STATUS_ADDR if self.slaves.contains_key(&self.cs) => STATUS_PRESENT,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u32, _sel: u8, data: u16) {
+        match addr {
+            DATA_ADDR => self.transfer(data as u8),
+            CTRL_ADDR => {
+                let done = self.ctrl & !data & CTRL_DONE;
+                self.ctrl = (data & CTRL_IRQ_EN) | done;
+                self.update_irq();
+            },
+            CS_ADDR if data != self.cs => {
+                if let Some(slave) = self.slaves.get_mut(&self.cs) {
+                    slave.select(false);
+                }
+                self.cs = data;
+                if let Some(slave) = self.slaves.get_mut(&self.cs) {
+                    slave.select(true);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        self.write(CS_ADDR, 0b11, 0);
+        self.rx = 0xff;
+        self.ctrl = 0;
+        self.update_irq();
+    }
+}
+
+impl SpiController {
+    pub fn new(irq: IrqLine) -> SpiController {
+        // CS 0 is selected after reset, so software that doesn't know about CS can talk to first device
+        SpiController { slaves: BTreeMap::new(), cs: 0, rx: 0xff, ctrl: 0, irq }
+    }
+
+    pub fn attach(&mut self, cs: u16, slave: Box<dyn SpiSlave>) {
+        self.slaves.insert(cs, slave);
+    }
+
+    fn transfer(&mut self, mosi: u8) {
+        self.rx = match self.slaves.get_mut(&self.cs) {
+            Some(slave) => slave.transfer(mosi),
+            None => 0xff, // MISO pulled up
+        };
+        if self.slaves.get_mut(&self.cs).is_some_and(|slave| slave.take_transfer_done()) {
+            self.ctrl |= CTRL_DONE;
+            self.update_irq();
+        }
+    }
+
+    fn update_irq(&self) {
+        self.irq.set((self.ctrl & CTRL_IRQ_EN) != 0 && (self.ctrl & CTRL_DONE) != 0);
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use super::spi::SpiSlave;
+
+// Serial NOR flash (25-series command set) backed by image file. Commands are framed by chip
+// select, program and erase operations are applied when CS is deasserted, as in real devices.
+
+pub struct SpiFlash {
+    file: File,
+    mem: Vec<u8>,
+
+    cmd: Option<u8>,
+    addr: u32,
+    addr_bytes: usize,
+    dummy_bytes: usize,
+    page_buf: BTreeMap<u32, u8>,
+
+    write_enable: bool,
+}
+
+const CMD_PAGE_PROGRAM: u8 = 0x02;
+const CMD_READ: u8 = 0x03;
+const CMD_WRITE_DISABLE: u8 = 0x04;
+const CMD_READ_STATUS: u8 = 0x05;
+const CMD_WRITE_ENABLE: u8 = 0x06;
+const CMD_FAST_READ: u8 = 0x0b;
+const CMD_SECTOR_ERASE: u8 = 0x20;
+const CMD_CHIP_ERASE: u8 = 0xc7;
+const CMD_CHIP_ERASE_ALT: u8 = 0x60;
+const CMD_BLOCK_ERASE: u8 = 0xd8;
+const CMD_READ_ID: u8 = 0x9f;
+
+const STATUS_WEL: u8 = 0b10;
+
+const PAGE_SIZE: u32 = 256;
+const SECTOR_SIZE: u32 = 4096;
+const BLOCK_SIZE: u32 = 65536;
+
+const JEDEC_MANUFACTURER: u8 = 0xef;
+const JEDEC_TYPE: u8 = 0x40;
+
+impl SpiSlave for SpiFlash {
+    fn transfer(&mut self, mosi: u8) -> u8 {
+        let Some(cmd) = self.cmd else {
+            self.start_cmd(mosi);
+            return 0xff;
+        };
+
+        if self.addr_bytes > 0 {
+            self.addr = (self.addr << 8) | mosi as u32;
+            self.addr_bytes -= 1;
+            if self.addr_bytes == 0 {
+                self.addr %= self.mem.len() as u32;
+            }
+            return 0xff;
+        }
+        if self.dummy_bytes > 0 {
+            self.dummy_bytes -= 1;
+            return 0xff;
+        }
+
+        match cmd {
+            CMD_READ | CMD_FAST_READ => {
+                let data = self.mem[self.addr as usize];
+                self.addr = (self.addr + 1) % self.mem.len() as u32;
+                data
+            },
+            CMD_READ_STATUS => if self.write_enable { STATUS_WEL } else { 0 },
+            CMD_READ_ID => {
+                let capacity = self.mem.len().trailing_zeros() as u8;
+                let id = [JEDEC_MANUFACTURER, JEDEC_TYPE, capacity];
+                let data = id[(self.addr as usize) % id.len()];
+                self.addr += 1;
+                data
+            },
+            CMD_PAGE_PROGRAM => {
+                // address wraps within page, later bytes replace earlier ones
+                self.page_buf.insert(self.addr, mosi);
+                self.addr = (self.addr & !(PAGE_SIZE-1)) | ((self.addr + 1) & (PAGE_SIZE-1));
+                0xff
+            },
+            _ => 0xff,
+        }
+    }
+
+    fn select(&mut self, selected: bool) {
+        if !selected {
+            self.finish_cmd();
+        }
+        self.cmd = None;
+        self.page_buf.clear();
+    }
+}
+
+impl SpiFlash {
+    pub fn new(mut file: File) -> io::Result<SpiFlash> {
+        let mut mem = Vec::new();
+        file.read_to_end(&mut mem)?;
+        if !mem.len().is_power_of_two() {
+            let msg = format!("image size must be power of two, got {} bytes", mem.len());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+
+        Ok(SpiFlash {
+            file, mem,
+            cmd: None, addr: 0, addr_bytes: 0, dummy_bytes: 0, page_buf: BTreeMap::new(),
+            write_enable: false,
+        })
+    }
+
+    fn start_cmd(&mut self, cmd: u8) {
+        self.cmd = Some(cmd);
+        self.addr = 0;
+        self.addr_bytes = 0;
+        self.dummy_bytes = 0;
+
+        match cmd {
+            CMD_READ | CMD_PAGE_PROGRAM | CMD_SECTOR_ERASE | CMD_BLOCK_ERASE => { self.addr_bytes = 3; },
+            CMD_FAST_READ => { self.addr_bytes = 3; self.dummy_bytes = 1; },
+            CMD_WRITE_ENABLE => { self.write_enable = true; },
+            CMD_WRITE_DISABLE => { self.write_enable = false; },
+            _ => {},
+        }
+    }
+
+    fn finish_cmd(&mut self) {
+        let Some(cmd) = self.cmd else {
+            return;
+        };
+        if !self.write_enable || self.addr_bytes > 0 {
+            return;
+        }
+
+        match cmd {
+            CMD_PAGE_PROGRAM => {
+                // programming can only clear bits
+                let page_buf = std::mem::take(&mut self.page_buf);
+                for (addr, data) in &page_buf {
+                    self.mem[*addr as usize] &= data;
+                }
+                if let (Some(min), Some(max)) = (page_buf.keys().next(), page_buf.keys().next_back()) {
+                    self.persist(*min, max+1);
+                }
+            },
+            CMD_SECTOR_ERASE => self.erase(self.addr & !(SECTOR_SIZE-1), SECTOR_SIZE),
+            CMD_BLOCK_ERASE => self.erase(self.addr & !(BLOCK_SIZE-1), BLOCK_SIZE),
+            CMD_CHIP_ERASE | CMD_CHIP_ERASE_ALT => self.erase(0, self.mem.len() as u32),
+            _ => return,
+        }
+        self.write_enable = false;
+    }
+
+    fn erase(&mut self, start: u32, len: u32) {
+        let end = (start + len).min(self.mem.len() as u32);
+        self.mem[start as usize..end as usize].fill(0xff);
+        self.persist(start, end);
+    }
+
+    fn persist(&mut self, start: u32, end: u32) {
+        self.file.seek(SeekFrom::Start(start as u64))
+            .and_then(|_| self.file.write_all(&self.mem[start as usize..end as usize]))
+            .expect("Failed to write SPI flash image");
+    }
+}
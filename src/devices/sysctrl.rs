@@ -0,0 +1,68 @@
+use super::bus::Device;
+
+// System control: guest requested reset and cause of the last reset. Writing RESET_KEY to RESET
+// resets the CPU and all devices at the end of current instruction, execution restarts from boot
+// ROM. CAUSE survives the reset, so guest can tell power-on from watchdog reset.
+
+pub struct SysCtrl {
+    cause: ResetCause,
+    reset_request: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ResetCause {
+    PowerOn = 0,
+    Software = 1,
+    Watchdog = 2,
+    Monitor = 3,
+}
+
+const RESET_ADDR: u32 = 0x0;
+const CAUSE_ADDR: u32 = 0x1;
+
+const RESET_KEY: u16 = 0xdead; // other values written to RESET are ignored
+
+impl Device for SysCtrl {
+    fn read(&mut self, addr: u32, _sel: u8) -> u16 {
+        match addr {
+            CAUSE_ADDR => self.cause as u16,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u32, _sel: u8, data: u16) {
+        if addr == RESET_ADDR && data == RESET_KEY {
+            self.reset_request = true;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.reset_request = false;
+    }
+}
+
+impl SysCtrl {
+    pub fn new() -> SysCtrl {
+        SysCtrl { cause: ResetCause::PowerOn, reset_request: false }
+    }
+
+    /// True once after guest requested reset
+    pub fn take_reset_request(&mut self) -> bool {
+        std::mem::take(&mut self.reset_request)
+    }
+
+    pub fn set_cause(&mut self, cause: ResetCause) {
+        self.cause = cause;
+    }
+}
+
+impl ResetCause {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            ResetCause::PowerOn => "power-on",
+            ResetCause::Software => "software",
+            ResetCause::Watchdog => "watchdog",
+            ResetCause::Monitor => "monitor",
+        }
+    }
+}
@@ -1,14 +1,159 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Instant;
+
 use super::bus::Device;
+use super::irqc::IrqLine;
+use crate::support::replay::{HostClock, InputLog};
+
+// Programmable up-counting timer. Counter is incremented every (PRESCALER+1) clock ticks and fires
+// when it reaches COMPARE, then it is reloaded with 0 (periodic mode) or stopped (one-shot).
 
 pub struct Timer {
+    clock: TimerClock,
+    host_start: Instant,
+    last_host_micros: i64,
+    log: Rc<RefCell<InputLog>>,
 
+    ctrl: u16,
+    counter: u16,
+    compare: u16,
+    prescaler: u16,
+    prescale_cnt: u64,
+
+    irq: IrqLine,
 }
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum TimerClock {
+    /// one tick per executed instruction (there is no separate cycle model, every instruction is one cycle)
+    Instructions,
+    /// one tick per microsecond of host wall-clock time
+    Host,
+}
+
+const CTRL_ADDR: u32 = 0x0;
+const COUNTER_ADDR: u32 = 0x1;
+const COMPARE_ADDR: u32 = 0x2;
+const PRESCALER_ADDR: u32 = 0x3;
+
+const CTRL_ENABLE: u16 = 0b0001;
+const CTRL_IRQ_EN: u16 = 0b0010;
+const CTRL_PERIODIC: u16 = 0b0100;
+const CTRL_FIRED: u16 = 0b1000; // write 1 to clear, irq line is held while set
+
 impl Device for Timer {
     fn read(&mut self, addr: u32, _sel: u8) -> u16 {
-       0 
+        match addr {
+            CTRL_ADDR => self.ctrl,
+            COUNTER_ADDR => self.counter,
+            COMPARE_ADDR => self.compare,
+            PRESCALER_ADDR => self.prescaler,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u32, _sel: u8, data: u16) {
+        match addr {
+            CTRL_ADDR => {
+                let fired = (self.ctrl & !data) & CTRL_FIRED;
+                // partial prescaler count is kept when ISR only clears FIRED
+                if self.ctrl & CTRL_ENABLE == 0 && data & CTRL_ENABLE != 0 {
+                    self.prescale_cnt = 0;
+                    if matches!(self.clock, TimerClock::Host) {
+                        self.last_host_micros = self.host_micros();
+                    }
+                }
+                self.ctrl = (data & !CTRL_FIRED) | fired;
+                self.update_irq();
+            },
+            COUNTER_ADDR => { self.counter = data; },
+            COMPARE_ADDR => { self.compare = data; },
+            PRESCALER_ADDR => {
+                self.prescaler = data;
+                self.prescale_cnt = 0;
+            },
+            _ => {},
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.ctrl & CTRL_ENABLE == 0 {
+            return;
+        }
+
+        let ticks = match self.clock {
+            TimerClock::Instructions => 1,
+            TimerClock::Host => {
+                // host time is only read while enabled, so it is not logged for idle timer
+                let now = self.host_micros();
+                let ticks = (now - self.last_host_micros).max(0) as u64; // replay log can go backwards
+                self.last_host_micros = now;
+                ticks
+            },
+        };
+
+        self.prescale_cnt += ticks;
+        let period = self.prescaler as u64 + 1;
+        let steps = self.prescale_cnt / period;
+        self.prescale_cnt %= period;
+        self.advance(steps);
+    }
+
+    fn reset(&mut self) {
+        self.ctrl = 0;
+        self.counter = 0;
+        self.compare = 0;
+        self.prescaler = 0;
+        self.prescale_cnt = 0;
+        self.update_irq();
+    }
+}
+
+impl Timer {
+    pub fn new(clock: TimerClock, log: Rc<RefCell<InputLog>>, irq: IrqLine) -> Timer {
+        Timer {
+            clock, host_start: Instant::now(), last_host_micros: 0, log,
+            ctrl: 0, counter: 0, compare: 0, prescaler: 0, prescale_cnt: 0,
+            irq,
+        }
+    }
+
+    fn host_micros(&self) -> i64 {
+        let start = self.host_start;
+        self.log.borrow_mut().host_time(HostClock::Timer, || start.elapsed().as_micros() as i64)
+    }
+
+    fn advance(&mut self, mut steps: u64) {
+        while steps > 0 {
+            let to_match = match self.compare.wrapping_sub(self.counter) {
+                0 => 0x1_0000,
+                n => n as u64,
+            };
+            if steps < to_match {
+                self.counter = self.counter.wrapping_add(steps as u16);
+                return;
+            }
+
+            steps -= to_match;
+            self.counter = self.compare;
+            self.fire();
+
+            if self.ctrl & CTRL_PERIODIC != 0 {
+                self.counter = 0;
+            } else {
+                self.ctrl &= !CTRL_ENABLE;
+                return;
+            }
+        }
+    }
+
+    fn fire(&mut self) {
+        self.ctrl |= CTRL_FIRED;
+        self.update_irq();
     }
 
-    fn write(&mut self, addr: u32, sel: u8, data: u16) {
+    fn update_irq(&self) {
+        self.irq.set((self.ctrl & CTRL_FIRED) != 0 && (self.ctrl & CTRL_IRQ_EN) != 0);
     }
 }
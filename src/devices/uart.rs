@@ -1,24 +1,65 @@
-use std::io::Write;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
 
 use crate::support::tty::Pty;
 use crate::devices::bus::Device;
+use crate::devices::irqc::IrqLine;
+use crate::support::text::parse_text;
+use crate::support::time::INSTRUCTIONS_PER_SECOND;
+use crate::support::replay::{InputLog, InputEvent};
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct UART {
-    pub pty: Pty,
-    
+    host: SerialHost,
+    log: Rc<RefCell<InputLog>>,
+    rx_fifo: VecDeque<u8>, // host input is moved here between instructions
+    tx_capture: Option<Vec<u8>>, // transmitted bytes not yet taken by script
+
     last_read: u8,
-    last_read_pending: bool
+    last_read_pending: bool,
+
+    ctrl: u16,
+    irq: IrqLine,
+}
+
+/// Host side of the serial line
+pub enum SerialHost {
+    /// interactive terminal
+    Pty(Pty),
+    /// fixed input fed to RX, TX written to output (file or stdout)
+    Headless { input: VecDeque<u8>, output: Box<dyn Write> },
 }
 
 const STATUS_ADDR: u32 = 0x0;
 const RX_ADDR: u32 = 0x1;
 const TX_ADDR: u32 = 0x2;
+const CTRL_ADDR: u32 = 0x3;
+
+const STATUS_RX_READY: u16 = 0b01;
+const STATUS_TX_READY: u16 = 0b10;
+
+const CTRL_RX_IRQ_EN: u16 = 0b01;
+const CTRL_TX_IRQ_EN: u16 = 0b10;
 
 impl Device for UART {
     fn write(&mut self, address: u32, _sel: u8, data: u16) {
-        if address == TX_ADDR {
-            print!("txx{}", data as u8 as char);
-            self.pty.master_write_file.write(&[data as u8]).unwrap();
+        match address {
+            TX_ADDR => {
+                crate::trace!("txx{}", data as u8 as char);
+                self.host.write(data as u8);
+                if let Some(capture) = &mut self.tx_capture {
+                    capture.push(data as u8);
+                }
+            },
+            CTRL_ADDR => {
+                self.ctrl = data & (CTRL_RX_IRQ_EN | CTRL_TX_IRQ_EN);
+                self.update_irq();
+            },
+            _ => {}
         }
     }
 
@@ -26,34 +67,193 @@ impl Device for UART {
         match address {
             STATUS_ADDR => {
                 // check if new value is available and share it to reading
-                if !self.last_read_pending { // peeking is not possible between calls, so this workaround :(
-                    let read = self.pty.master_reciever.try_recv();
-                    if read.is_ok() {
-                        self.last_read = read.unwrap();
-                        self.last_read_pending = true;
-                    }
-                }
-                
-                let tx_ready = 1;
-
-                self.last_read_pending as u16 | (tx_ready<<1)
+                self.poll_rx();
+                self.status()
             },
             RX_ADDR => {
                 if !self.last_read_pending {
                     // try reading new value
-                    self.last_read = self.pty.master_reciever.try_recv().unwrap_or(self.last_read);
+                    self.last_read = self.next_rx().unwrap_or(self.last_read);
                 }
                 self.last_read_pending = false;
+                self.update_irq();
                 self.last_read as u16
-            }
+            },
+            CTRL_ADDR => self.ctrl,
             _ => 0
         }
     }
+
+    fn tick(&mut self) {
+        if self.ctrl & CTRL_RX_IRQ_EN != 0 {
+            self.poll_rx();
+            self.update_irq();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ctrl = 0;
+        self.rx_fifo.clear();
+        self.last_read_pending = false;
+        self.update_irq();
+    }
 }
 
 impl UART {
-    pub fn new() -> UART {
-        let pty = Pty::open().expect("Failed to open PTY terminal pair");
-        UART { pty, last_read: 0, last_read_pending: false }
+    pub fn new(host: SerialHost, log: Rc<RefCell<InputLog>>, irq: IrqLine) -> UART {
+        UART { host, log, rx_fifo: VecDeque::new(), tx_capture: None, last_read: 0, last_read_pending: false, ctrl: 0, irq }
+    }
+
+    /// Queue bytes to be received by guest
+    pub fn send(&mut self, data: &[u8]) {
+        self.log.borrow_mut().input(&InputEvent::UartRx(data.to_vec()));
+        self.rx_fifo.extend(data);
+    }
+
+    /// Receive headless input, called between instructions (terminal input arrives as host events)
+    pub fn poll_host(&mut self) {
+        let SerialHost::Headless { input, .. } = &mut self.host else {
+            return;
+        };
+        if !input.is_empty() {
+            let data: Vec<u8> = input.drain(..).collect();
+            self.send(&data);
+        }
+    }
+
+    /// Bytes transmitted by guest since previous call, capture starts with the first call
+    pub fn take_tx(&mut self) -> Vec<u8> {
+        self.tx_capture.replace(Vec::new()).unwrap_or_default()
+    }
+
+    pub fn stop_tx_capture(&mut self) {
+        self.tx_capture = None;
+    }
+
+    fn next_rx(&mut self) -> Option<u8> {
+        self.rx_fifo.pop_front()
+    }
+
+    fn poll_rx(&mut self) {
+        if !self.last_read_pending { // peeking is not possible between calls, so this workaround :(
+            if let Some(read) = self.next_rx() {
+                self.last_read = read;
+                self.last_read_pending = true;
+            }
+        }
+    }
+
+    fn status(&self) -> u16 {
+        let tx_ready = STATUS_TX_READY; // writes are never blocking
+        (if self.last_read_pending { STATUS_RX_READY } else { 0 }) | tx_ready
+    }
+
+    // lines are level triggered, held as long as condition is present
+    fn update_irq(&self) {
+        let status = self.status();
+        let rx = (self.ctrl & CTRL_RX_IRQ_EN) != 0 && (status & STATUS_RX_READY) != 0;
+        let tx = (self.ctrl & CTRL_TX_IRQ_EN) != 0 && (status & STATUS_TX_READY) != 0;
+        self.irq.set(rx || tx);
+    }
+}
+
+impl SerialHost {
+    fn write(&mut self, byte: u8) {
+        match self {
+            SerialHost::Pty(pty) => pty.master_write_file.write_all(&[byte]).unwrap(),
+            SerialHost::Headless { output, .. } => output.write_all(&[byte]).expect("Failed to write UART output"),
+        }
+    }
+}
+
+/// Console automation script run against UART at instruction granularity, one command per line:
+/// `send "text"`, `expect "text"` (waits for guest output), `timeout <instructions>` (of following
+/// expects), `wait <instructions>`, `sleep <seconds>` (simulated time)
+pub struct UartScript {
+    steps: Vec<(usize, ScriptStep)>, // line number, step
+    pos: usize,
+    step_start: Option<u64>,
+    timeout: u64,
+    output: Vec<u8>, // guest output not yet matched by expect
+    last_expect: Option<usize>, // output is captured only until this step
+}
+
+enum ScriptStep {
+    Send(Vec<u8>),
+    Expect(Vec<u8>),
+    Timeout(u64),
+    Wait(u64),
+}
+
+const DEFAULT_EXPECT_TIMEOUT: u64 = 10 * INSTRUCTIONS_PER_SECOND;
+
+impl UartScript {
+    pub fn load(path: &Path) -> io::Result<UartScript> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+
+        let mut steps = Vec::new();
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |msg: String| io::Error::new(io::ErrorKind::InvalidData, format!("UART script line {}: {}", lineno+1, msg));
+            let (command, arg) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let arg = arg.trim();
+            let count = || arg.parse::<u64>().map_err(|_| error(format!("invalid instruction count `{}`", arg)));
+            let step = match command {
+                "send" => ScriptStep::Send(parse_text(arg).map_err(error)?.into_bytes()),
+                "expect" => ScriptStep::Expect(parse_text(arg).map_err(error)?.into_bytes()),
+                "timeout" => ScriptStep::Timeout(count()?),
+                "wait" => ScriptStep::Wait(count()?),
+                "sleep" => match arg.parse::<f64>() {
+                    Ok(secs) if secs >= 0.0 => ScriptStep::Wait((secs * INSTRUCTIONS_PER_SECOND as f64) as u64),
+                    _ => return Err(error(format!("invalid number of seconds `{}`", arg))),
+                },
+                _ => return Err(error(format!("unknown command `{}`", command))),
+            };
+            steps.push((lineno+1, step));
+        }
+        let last_expect = steps.iter().rposition(|(_, step)| matches!(step, ScriptStep::Expect(_)));
+        Ok(UartScript { steps, pos: 0, step_start: None, timeout: DEFAULT_EXPECT_TIMEOUT, output: Vec::new(), last_expect })
+    }
+
+    /// Run script steps until one has to wait, error when expect times out
+    pub fn apply(&mut self, instructions: u64, uart: &mut UART) -> Result<(), String> {
+        if self.last_expect.is_some_and(|last| self.pos <= last) {
+            self.output.extend(uart.take_tx());
+        } else if self.last_expect.take().is_some() {
+            uart.stop_tx_capture();
+            self.output = Vec::new();
+        }
+        while let Some((lineno, step)) = self.steps.get(self.pos) {
+            let start = *self.step_start.get_or_insert(instructions);
+            match step {
+                ScriptStep::Send(data) => uart.send(data),
+                ScriptStep::Expect(pattern) => {
+                    let found = match pattern.is_empty() {
+                        true => Some(0),
+                        false => self.output.windows(pattern.len()).position(|w| w == &pattern[..]),
+                    };
+                    match found {
+                        Some(pos) => { self.output.drain(..pos + pattern.len()); },
+                        None if instructions - start >= self.timeout => {
+                            return Err(format!("line {}: timed out waiting for {:?}", lineno, String::from_utf8_lossy(pattern)));
+                        },
+                        None => return Ok(()),
+                    }
+                },
+                ScriptStep::Timeout(timeout) => { self.timeout = *timeout; },
+                ScriptStep::Wait(count) => {
+                    if instructions - start < *count {
+                        return Ok(());
+                    }
+                },
+            }
+            self.pos += 1;
+            self.step_start = None;
+        }
+        Ok(())
     }
 }
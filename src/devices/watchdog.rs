@@ -0,0 +1,114 @@
+use super::bus::Device;
+use super::irqc::IrqLine;
+use crate::support::time::INSTRUCTIONS_PER_SECOND;
+
+// Watchdog timer counting down milliseconds of simulated time (executed instructions). Guest has to
+// write KICK_KEY to KICK before counter runs out. First expiry sets EXPIRED and raises interrupt,
+// counter is reloaded, and if it expires again before guest kicks the watchdog, system is reset.
+
+pub struct Watchdog {
+    load: u16,
+    counter: u16,
+    sub_ms: u64,
+    ctrl: u16,
+    reset_request: bool,
+    irq: IrqLine,
+}
+
+const LOAD_ADDR: u32 = 0x0; // period in milliseconds
+const KICK_ADDR: u32 = 0x1;
+const CTRL_ADDR: u32 = 0x2;
+const COUNTER_ADDR: u32 = 0x3; // milliseconds left
+
+const CTRL_ENABLE: u16 = 0b001;
+const CTRL_IRQ_EN: u16 = 0b010;
+const CTRL_EXPIRED: u16 = 0b100; // write 1 to clear, next expiry resets the system while set
+
+const KICK_KEY: u16 = 0x5afe; // other values written to KICK are ignored
+
+const INSTRUCTIONS_PER_MS: u64 = INSTRUCTIONS_PER_SECOND / 1000;
+
+impl Device for Watchdog {
+    fn read(&mut self, addr: u32, _sel: u8) -> u16 {
+        match addr {
+            LOAD_ADDR => self.load,
+            CTRL_ADDR => self.ctrl,
+            COUNTER_ADDR => self.counter,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u32, _sel: u8, data: u16) {
+        match addr {
+            LOAD_ADDR => { self.load = data; },
+            KICK_ADDR if data == KICK_KEY => {
+                self.reload();
+                self.ctrl &= !CTRL_EXPIRED;
+            },
+            CTRL_ADDR => {
+                let expired = self.ctrl & !data & CTRL_EXPIRED;
+                if self.ctrl & CTRL_ENABLE == 0 && data & CTRL_ENABLE != 0 {
+                    self.reload();
+                }
+                self.ctrl = (data & (CTRL_ENABLE | CTRL_IRQ_EN)) | expired;
+            },
+            _ => {},
+        }
+        self.update_irq();
+    }
+
+    fn tick(&mut self) {
+        if self.ctrl & CTRL_ENABLE == 0 {
+            return;
+        }
+        self.sub_ms += 1;
+        if self.sub_ms < INSTRUCTIONS_PER_MS {
+            return;
+        }
+        self.sub_ms = 0;
+        self.counter = self.counter.saturating_sub(1);
+        if self.counter > 0 {
+            return;
+        }
+
+        if self.ctrl & CTRL_EXPIRED != 0 {
+            self.reset_request = true;
+        }
+        self.ctrl |= CTRL_EXPIRED;
+        self.reload();
+        self.update_irq();
+    }
+
+    fn reset(&mut self) {
+        self.load = 0;
+        self.counter = 0;
+        self.sub_ms = 0;
+        self.ctrl = 0;
+        self.reset_request = false;
+        self.update_irq();
+    }
+}
+
+impl Watchdog {
+    pub fn new(irq: IrqLine) -> Watchdog {
+        Watchdog { load: 0, counter: 0, sub_ms: 0, ctrl: 0, reset_request: false, irq }
+    }
+
+    /// Returns true (once) when watchdog expired second time and system has to be reset
+    pub fn take_reset_request(&mut self) -> bool {
+        std::mem::take(&mut self.reset_request)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.ctrl & CTRL_ENABLE != 0
+    }
+
+    fn reload(&mut self) {
+        self.counter = self.load.max(1);
+        self.sub_ms = 0;
+    }
+
+    fn update_irq(&self) {
+        self.irq.set((self.ctrl & CTRL_IRQ_EN) != 0 && (self.ctrl & CTRL_EXPIRED) != 0);
+    }
+}
@@ -1,7 +1,9 @@
 
 mod cpu;
+mod debug;
 mod devices;
 mod support;
+mod test_runner;
 
 #[macro_use]
 extern crate lazy_static;
@@ -10,60 +12,341 @@ extern crate lazy_static;
 use core::time;
 use std::thread;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write, BufWriter};
 use std::rc::Rc;
 use std::cell::RefCell;
 
-use clap::Parser;
+use clap::{Parser, Subcommand, Args};
 
-use crate::devices::bus::{Bus, DeviceEntry, Device};
-use crate::devices::irqc::Irqc;
+use crate::devices::bus::{Bus, BusMaster, DeviceEntry, Device};
+use crate::devices::irqc::{Irqc, IrqLine, IRQ_UART, IRQ_TIMER, IRQ_SPI, IRQ_GPIO, IRQ_KEYBOARD, IRQ_RTC, IRQ_DMA, IRQ_WATCHDOG, IRQ_HOSTFS};
 use crate::devices::ram::RAM;
 use crate::devices::rom::ROM;
-use crate::devices::sd::SD;
-use crate::devices::uart::UART;
-use crate::devices::timer::Timer;
+use crate::devices::disk::{DiskImage, Overlay};
+use crate::devices::sd::{SD, FaultInjector};
+use crate::devices::spi::SpiController;
+use crate::devices::spi_flash::SpiFlash;
+use crate::devices::uart::{UART, SerialHost, UartScript};
+use crate::devices::framebuffer::{Framebuffer, FramebufferOutput, VRAM_WORDS};
+use crate::devices::keyboard::{Keyboard, KeyboardScript, KeyEvent};
+use crate::devices::rtc::{Rtc, RtcSource};
+use crate::devices::dma::Dma;
+use crate::devices::sim_control::SimControl;
+use crate::devices::watchdog::Watchdog;
+use crate::devices::sysctrl::{SysCtrl, ResetCause};
+use crate::devices::hostfs::HostFs;
+use crate::devices::gpio::{Gpio, GpioScript, parse_pin, parse_level};
+use crate::devices::timer::{Timer, TimerClock};
 
-use crate::cpu::cpu::CPU;
+use crate::test_runner::{run_tests, TestOptions};
+use crate::cpu::cpu::{CPU, ExecHook};
+use crate::cpu::instr::{Encoding, disassemble};
+use crate::debug::symbols::{SymbolTable, parse_addr};
+use crate::debug::profile::Profiler;
+use crate::debug::coverage::{Coverage, LineMap};
+use crate::support::{monitor, signal, trace};
+use crate::support::events::{HostEvents, HostEvent};
+use crate::support::image::ImageFormat;
+use crate::support::tty::Pty;
+use crate::support::time::parse_time;
+use crate::support::sandbox::Sandbox;
+use crate::support::replay::{InputLog, InputEvent};
+use crate::support::fat::{self, FatType, ImageOptions};
 
-fn build_system(prog_init: &[u8], data_init: &[u8], sd_file: File) {
-    let mut bus = Bus::new();
+// Host side configuration of emulated devices
+struct SystemConfig {
+    serial: SerialHost,
+    uart_script: Option<UartScript>,
+    sd_image: DiskImage,
+    sd_fault: Option<FaultInjector>,
+    spi_flash: Option<SpiFlash>,
+    timer_clock: TimerClock,
+    rtc_source: RtcSource,
+    gpio_in: u16,
+    gpio_script: Option<GpioScript>,
+    vga_output: FramebufferOutput,
+    kbd_term: Option<Pty>,
+    kbd_script: Option<KeyboardScript>,
+    monitor: bool,
+    host_queue: Option<usize>, // capacity of host event queue, unbounded if None
+    input_log: InputLog,
+    host_dir: Option<Sandbox>,
+    reset_keep_ram: bool, // RAM and VRAM survive system reset, otherwise images are reloaded
+    limits: RunLimits,
+}
+
+struct RunLimits {
+    max_instructions: Option<u64>,
+    timeout: Option<time::Duration>,
+    idle_pc: Option<u16>,
+    stop_on_halt: bool,
+}
+
+struct RunResult {
+    stop: StopReason,
+    instructions: u64,
+    cpu_state: String, // register dump at the end of run
+}
+
+/// Why simulation loop ended, each has its own process exit code. Statuses 120-125 are reserved for
+/// simulator stops, guest exit codes above GUEST_EXIT_MAX are clamped to it.
+enum StopReason {
+    Interrupted,
+    GuestExit(u16),
+    MaxInstructions,
+    Timeout,
+    Halted,
+    IdlePc,
+    ScriptFailed(String),
+}
+
+const GUEST_EXIT_MAX: u16 = 119;
+
+impl StopReason {
+    fn exit_code(&self) -> i32 {
+        match self {
+            StopReason::Interrupted => 0,
+            StopReason::GuestExit(code) => (*code).min(GUEST_EXIT_MAX) as i32,
+            StopReason::MaxInstructions => 121,
+            StopReason::Halted => 122,
+            StopReason::IdlePc => 123,
+            StopReason::Timeout => 124, // same as timeout(1)
+            StopReason::ScriptFailed(_) => 125,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            StopReason::Interrupted => "interrupted".to_string(),
+            StopReason::GuestExit(code) => format!("guest exited with code {}", code),
+            StopReason::MaxInstructions => "instruction limit reached".to_string(),
+            StopReason::Halted => "guest halted (jump to self with interrupts disabled)".to_string(),
+            StopReason::IdlePc => "guest reached idle pc".to_string(),
+            StopReason::Timeout => "timeout".to_string(),
+            StopReason::ScriptFailed(msg) => format!("UART script failed at {}", msg),
+        }
+    }
+}
+
+fn build_system(prog_init: &[u8], data_init: &[u8], config: SystemConfig, symbols: Rc<SymbolTable>, hooks: Vec<Rc<RefCell<dyn ExecHook>>>) -> RunResult {
+    let mut bus = Bus::new(Rc::clone(&symbols));
 
     const RAM_START: u32 = 0x10_0000;
     const RAM_END: u32   = 0xff_dfff;
 
-    let mut ram = RAM::with_size((RAM_END-RAM_START) as usize);
-    ram.load_at(0x80_0000-RAM_START, prog_init);
-    ram.load_at(0x10_0800-RAM_START, data_init);
-    bus.add_device(DeviceEntry {begin_addr: RAM_START, end_addr: RAM_END, device: Rc::new(RefCell::new(ram))});
+    let ram = Rc::new(RefCell::new(RAM::with_size((RAM_END-RAM_START) as usize)));
+    let load_images = |ram: &mut RAM| {
+        ram.load_at(0x80_0000-RAM_START, prog_init);
+        ram.load_at(0x10_0800-RAM_START, data_init);
+    };
+    load_images(&mut ram.borrow_mut());
+    bus.add_device(DeviceEntry {begin_addr: RAM_START, end_addr: RAM_END, device: Rc::clone(&ram) as Rc<RefCell<dyn Device>>});
 
-    let serial = UART::new();
-    serial.pty.spawn_term();
-    bus.add_device(DeviceEntry {begin_addr: 0x002000, end_addr: 0x002002, device: Rc::new(RefCell::new(serial))});
+    let irqc = Rc::new(RefCell::new(Irqc::new()));
+    bus.add_device(DeviceEntry { device: Rc::clone(&irqc) as Rc<RefCell<dyn Device>>, begin_addr: 0x00200c, end_addr: 0x00200e });
+
+    let input_log = Rc::new(RefCell::new(config.input_log));
+    let replay = input_log.borrow().is_replay();
+
+    // host threads feed this queue, it is drained by simulation loop
+    let host_events = HostEvents::new(config.host_queue);
+
+    let serial_term = matches!(config.serial, SerialHost::Pty(_));
+    if let SerialHost::Pty(pty) = &config.serial {
+        pty.spawn_term();
+        pty.spawn_reader(host_events.sender(), HostEvent::Serial);
+    }
+    let serial = Rc::new(RefCell::new(UART::new(config.serial, Rc::clone(&input_log), IrqLine::new(&irqc, IRQ_UART))));
+    bus.add_device(DeviceEntry {begin_addr: 0x002000, end_addr: 0x002003, device: Rc::clone(&serial) as Rc<RefCell<dyn Device>>});
+    let mut uart_script = config.uart_script;
 
     let boot_rom = ROM::new(&BOOTJUMP_ROM);
     bus.add_device(DeviceEntry { device: Rc::new(RefCell::new(boot_rom)), begin_addr: 0xff_e000, end_addr: 0xff_e005 });
-    thread::sleep(time::Duration::from_millis(100)); // TODO: wait for xterm lanuch to not miss serial out
+    if serial_term {
+        thread::sleep(time::Duration::from_millis(100)); // TODO: wait for xterm lanuch to not miss serial out
+    }
 
-    let irqc = Rc::new(RefCell::new(Irqc::new()));
-    bus.add_device(DeviceEntry { device: Rc::clone(&irqc) as Rc<RefCell<dyn Device>>, begin_addr: 0x00200c, end_addr: 0x00200e });
-    
-    let timer = Rc::new(RefCell::new(Timer{}));
-    bus.add_device(DeviceEntry { device: Rc::clone(&timer) as Rc<RefCell<dyn Device>>, begin_addr: 0x002008, end_addr: 0x00200a });
+    let timer = Rc::new(RefCell::new(Timer::new(config.timer_clock, Rc::clone(&input_log), IrqLine::new(&irqc, IRQ_TIMER))));
+    bus.add_device(DeviceEntry { device: Rc::clone(&timer) as Rc<RefCell<dyn Device>>, begin_addr: 0x002008, end_addr: 0x00200b });
     
-    let spi_sd = SD::new(sd_file);
-    bus.add_device(DeviceEntry { device: Rc::new(RefCell::new(spi_sd)) as Rc<RefCell<dyn Device>>, begin_addr: 0x002010, end_addr: 0x002014 });
+    let rtc = Rtc::new(config.rtc_source, Rc::clone(&input_log), IrqLine::new(&irqc, IRQ_RTC));
+    bus.add_device(DeviceEntry { device: Rc::new(RefCell::new(rtc)) as Rc<RefCell<dyn Device>>, begin_addr: 0x002030, end_addr: 0x002038 });
 
-    let mut cpu = CPU::new(bus, 0);
+    let mut spi = SpiController::new(IrqLine::new(&irqc, IRQ_SPI));
+    spi.attach(0, Box::new(SD::new(config.sd_image, config.sd_fault)));
+    if let Some(flash) = config.spi_flash {
+        spi.attach(1, Box::new(flash));
+    }
+    bus.add_device(DeviceEntry { device: Rc::new(RefCell::new(spi)) as Rc<RefCell<dyn Device>>, begin_addr: 0x002010, end_addr: 0x002015 });
 
-    println!("init done");
-    loop {
+    let gpio = Rc::new(RefCell::new(Gpio::new(config.gpio_in, Rc::clone(&input_log), IrqLine::new(&irqc, IRQ_GPIO))));
+    bus.add_device(DeviceEntry { device: Rc::clone(&gpio) as Rc<RefCell<dyn Device>>, begin_addr: 0x002018, end_addr: 0x00201c });
+    let mut gpio_script = config.gpio_script;
+
+    let vram = Rc::new(RefCell::new(RAM::with_size(VRAM_WORDS)));
+    bus.add_device(DeviceEntry { device: Rc::clone(&vram) as Rc<RefCell<dyn Device>>, begin_addr: 0x01_0000, end_addr: 0x01_ffff });
+    let framebuffer = Rc::new(RefCell::new(Framebuffer::new(Rc::clone(&vram), config.vga_output)));
+    bus.add_device(DeviceEntry { device: Rc::clone(&framebuffer) as Rc<RefCell<dyn Device>>, begin_addr: 0x002020, end_addr: 0x002024 });
+
+    let kbd_term = config.kbd_term;
+    if let Some(term) = &kbd_term {
+        term.spawn_reader(host_events.sender(), HostEvent::KeyboardTerm);
+    }
+    let keyboard = Rc::new(RefCell::new(Keyboard::new(Rc::clone(&input_log), IrqLine::new(&irqc, IRQ_KEYBOARD))));
+    bus.add_device(DeviceEntry { device: Rc::clone(&keyboard) as Rc<RefCell<dyn Device>>, begin_addr: 0x002028, end_addr: 0x00202a });
+    let mut kbd_script = config.kbd_script;
+
+    let dma = Rc::new(RefCell::new(Dma::new(IrqLine::new(&irqc, IRQ_DMA))));
+    bus.add_device(DeviceEntry { device: Rc::clone(&dma) as Rc<RefCell<dyn Device>>, begin_addr: 0x002040, end_addr: 0x002045 });
+    bus.add_master(dma as Rc<RefCell<dyn BusMaster>>);
+
+    let watchdog = Rc::new(RefCell::new(Watchdog::new(IrqLine::new(&irqc, IRQ_WATCHDOG))));
+    bus.add_device(DeviceEntry { device: Rc::clone(&watchdog) as Rc<RefCell<dyn Device>>, begin_addr: 0x002058, end_addr: 0x00205b });
+
+    let sysctrl = Rc::new(RefCell::new(SysCtrl::new()));
+    bus.add_device(DeviceEntry { device: Rc::clone(&sysctrl) as Rc<RefCell<dyn Device>>, begin_addr: 0x00205c, end_addr: 0x00205d });
+
+    let hostfs = Rc::new(RefCell::new(HostFs::new(config.host_dir.clone(), IrqLine::new(&irqc, IRQ_HOSTFS))));
+    bus.add_device(DeviceEntry { device: Rc::clone(&hostfs) as Rc<RefCell<dyn Device>>, begin_addr: 0x002060, end_addr: 0x002069 });
+    bus.add_master(hostfs as Rc<RefCell<dyn BusMaster>>);
+
+    let sim_control = Rc::new(RefCell::new(SimControl::new(config.host_dir)));
+    bus.add_device(DeviceEntry { device: Rc::clone(&sim_control) as Rc<RefCell<dyn Device>>, begin_addr: 0x002048, end_addr: 0x002053 });
+
+    if config.monitor {
+        monitor::spawn_reader(host_events.sender());
+    }
+
+    let mut cpu = CPU::new(bus, 0, symbols);
+    for hook in hooks {
+        cpu.add_hook(hook);
+    }
+
+    trace!("init done");
+    let limits = config.limits;
+    let reset_keep_ram = config.reset_keep_ram;
+    let start = std::time::Instant::now();
+    let mut instructions: u64 = 0;
+    let stop = loop {
+        if signal::interrupted() {
+            break StopReason::Interrupted;
+        }
+        if limits.max_instructions.is_some_and(|max| instructions >= max) {
+            break StopReason::MaxInstructions;
+        }
+        if limits.timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+            break StopReason::Timeout;
+        }
+        if limits.idle_pc == Some(cpu.state.pc) {
+            break StopReason::IdlePc;
+        }
+
+        let mut monitor_reset = false;
+        // host inputs are applied only here, between instructions, so they can be recorded and replayed
+        input_log.borrow_mut().set_instructions(instructions);
+        if replay {
+            let events = input_log.borrow_mut().replayed_inputs();
+            for event in events {
+                match event {
+                    InputEvent::UartRx(data) => serial.borrow_mut().send(&data),
+                    InputEvent::Keyboard(data) => keyboard.borrow_mut().push(&data),
+                    InputEvent::Gpio(pin, level) => gpio.borrow_mut().set_input(pin, level),
+                    InputEvent::Reset => { monitor_reset = true; },
+                }
+            }
+        } else {
+            serial.borrow_mut().poll_host();
+        }
+        for event in host_events.drain() {
+            match event {
+                _ if replay => {}, // terminal input is ignored, recorded inputs are used instead
+                HostEvent::Serial(data) => serial.borrow_mut().send(&data),
+                HostEvent::KeyboardTerm(data) => keyboard.borrow_mut().term_received(&data),
+                HostEvent::Monitor(line) => {
+                    if monitor_command(&line, &gpio, &framebuffer, &keyboard) {
+                        input_log.borrow_mut().input(&InputEvent::Reset);
+                        monitor_reset = true;
+                    }
+                },
+            }
+        }
+        if !replay {
+            keyboard.borrow_mut().poll_term();
+        }
+        if let Some(script) = &mut gpio_script {
+            script.apply(instructions, &mut gpio.borrow_mut());
+        }
+        if let Some(script) = &mut kbd_script {
+            script.apply(instructions, &mut keyboard.borrow_mut());
+        }
+        if let Some(Err(msg)) = uart_script.as_mut().map(|script| script.apply(instructions, &mut serial.borrow_mut())) {
+            break StopReason::ScriptFailed(msg);
+        }
+
+        let fetch_addr = cpu.sregs.immu_translate(cpu.state.pc<<1);
         cpu.tick();
+        instructions += 1;
+        // nothing can change pc of jump to itself when interrupts are disabled
+        let halted = limits.stop_on_halt && !cpu.sregs.irq_enabled()
+            && cpu.sregs.immu_translate(cpu.state.pc<<1) == fetch_addr;
         
         if irqc.borrow().active() {
             cpu.sregs.add_interrupt(cpu::sreg::IRQF_EXT);
         }
+        if let Some(code) = sim_control.borrow().exit_code() {
+            break StopReason::GuestExit(code);
+        }
+
+        let reset_cause = if monitor_reset {
+            Some(ResetCause::Monitor)
+        } else if watchdog.borrow_mut().take_reset_request() {
+            Some(ResetCause::Watchdog)
+        } else if sysctrl.borrow_mut().take_reset_request() {
+            Some(ResetCause::Software)
+        } else {
+            None
+        };
+        if let Some(cause) = reset_cause {
+            println!("system reset ({}) at {} instructions", cause.describe(), instructions);
+            cpu.reset();
+            sysctrl.borrow_mut().set_cause(cause);
+            if !reset_keep_ram {
+                ram.borrow_mut().clear();
+                load_images(&mut ram.borrow_mut());
+                vram.borrow_mut().clear();
+            }
+        }
+        // guest spinning with watchdog running is waiting for reset, not halted
+        if halted && reset_cause.is_none() && !watchdog.borrow().enabled() {
+            break StopReason::Halted;
+        }
+    };
+
+    framebuffer.borrow().final_snapshot();
+    input_log.borrow_mut().finish().expect("Failed to write input log");
+    RunResult { stop, instructions, cpu_state: cpu.dump_state() }
+}
+
+/// Execute monitor command, returns true if system should be reset
+fn monitor_command(line: &str, gpio: &RefCell<Gpio>, framebuffer: &RefCell<Framebuffer>, keyboard: &RefCell<Keyboard>) -> bool {
+    let args: Vec<_> = line.split_whitespace().collect();
+    match args[..] {
+        ["reset"] => return true,
+        [] => {},
+        ["gpio"] => println!("{}", gpio.borrow().status_line()),
+        ["gpio", pin, level] => match (parse_pin(pin), parse_level(level)) {
+            (Some(pin), Some(level)) => gpio.borrow_mut().set_input(pin, level),
+            _ => println!("monitor: usage: gpio <pin 0-15> <0|1>"),
+        },
+        ["key", ..] => match KeyEvent::parse(line.trim().strip_prefix("key").unwrap()) {
+            Ok(event) => event.apply(&mut keyboard.borrow_mut()),
+            Err(e) => println!("monitor: {}", e),
+        },
+        ["screenshot"] => framebuffer.borrow_mut().snapshot(),
+        ["screenshot", path] => framebuffer.borrow().write_snapshot(std::path::Path::new(path)),
+        _ => println!("monitor: unknown command `{}` (commands: gpio [<pin> <0|1>], key <action>, screenshot [<path>], reset)", line.trim()),
     }
+    false
 }
 
 const BOOTJUMP_ROM: [u16; 6] = [
@@ -76,28 +359,425 @@ const BOOTJUMP_ROM: [u16; 6] = [
 ];
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct CliArgs {
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Subcommand)]
+enum CliCommand {
+    /// Print disassembly of instruction binary
+    Disasm {
+        /// path of binary file with instructions
+        prog_bin_path: std::path::PathBuf,
+        /// symbol map (`name addr` lines) or ELF file with symbols
+        #[arg(long)]
+        symbols: Option<std::path::PathBuf>,
+    },
+    /// Fold blocks written to SD overlay file back to the base image and clear the overlay
+    Commit {
+        /// path of sd card image file
+        sd_img_path: std::path::PathBuf,
+        /// path of overlay file created with --sd-overlay
+        overlay_path: std::path::PathBuf,
+    },
+    /// Run golden output tests, every subdirectory of <dir> with prog.bin is a test case
+    /// (optional data.bin, sd.img, uart.in, uart.script, expected UART output in expected.txt)
+    Test {
+        /// directory with test cases
+        dir: std::path::PathBuf,
+        /// instruction limit of each test
+        #[arg(long, default_value_t = 100_000_000)]
+        max_instructions: u64,
+        /// host time limit of each test in seconds
+        #[arg(long, default_value = "60", value_parser = parse_seconds)]
+        timeout: time::Duration,
+        /// number of tests run in parallel (default: number of host CPUs)
+        #[arg(long)]
+        jobs: Option<usize>,
+        /// write actual output to expected.txt of tests that don't match
+        #[arg(long)]
+        update: bool,
+    },
+    /// Build FAT formatted SD card image with content of host directory
+    Mkimage {
+        /// directory with files to put in the image
+        dir: std::path::PathBuf,
+        /// path of created image file
+        output: std::path::PathBuf,
+        #[command(flatten)]
+        fat: FatArgs,
+    },
+}
+
+#[derive(Args)]
+struct FatArgs {
+    /// size of FAT image in MiB
+    #[arg(long, default_value_t = 64)]
+    image_size: u64,
+    /// FAT variant of the image
+    #[arg(long, value_enum, default_value_t = FatType::Fat32)]
+    fat: FatType,
+    /// put filesystem at the start of the image instead of the first MBR partition
+    #[arg(long)]
+    no_partition_table: bool,
+}
+
+impl FatArgs {
+    fn options(&self) -> ImageOptions {
+        ImageOptions { size: self.image_size << 20, fat_type: self.fat, partition_table: !self.no_partition_table }
+    }
+}
+
+#[derive(Args)]
+struct RunArgs {
     /// path of binary file with instructions (loaded to 0x800000)
-    prog_bin_path: std::path::PathBuf,
+    #[arg(required = true)]
+    prog_bin_path: Option<std::path::PathBuf>,
     /// path of binary file with data (loaded to 0x100000)
-    data_bin_path: std::path::PathBuf,
-    /// path of sd card image file
-    sd_img_path: std::path::PathBuf,
+    #[arg(required = true)]
+    data_bin_path: Option<std::path::PathBuf>,
+    /// path of sd card image file (not modified unless --sd-direct is given)
+    #[arg(required_unless_present = "sd_dir")]
+    sd_img_path: Option<std::path::PathBuf>,
+    /// symbol map (`name addr` lines) or ELF file with symbols, used to annotate traces
+    #[arg(long)]
+    symbols: Option<std::path::PathBuf>,
+    /// write instruction counts per function and pc to file on exit (Ctrl-C)
+    #[arg(long)]
+    profile: Option<std::path::PathBuf>,
+    /// write profiled call stacks in collapsed (flamegraph) format to file
+    #[arg(long)]
+    profile_stacks: Option<std::path::PathBuf>,
+    /// record executed instructions to coverage data file, merging with counts already in it
+    #[arg(long)]
+    coverage: Option<std::path::PathBuf>,
+    /// source line map (`addr file:line` lines) used by coverage reports
+    #[arg(long)]
+    line_map: Option<std::path::PathBuf>,
+    /// write coverage in lcov format (requires --line-map)
+    #[arg(long, requires = "line_map")]
+    coverage_lcov: Option<std::path::PathBuf>,
+    /// write program disassembly annotated with execution counts
+    #[arg(long)]
+    coverage_listing: Option<std::path::PathBuf>,
+    /// keep SD card writes in overlay file instead of memory, so they persist across runs (see `commit`)
+    #[arg(long)]
+    sd_overlay: Option<std::path::PathBuf>,
+    /// write SD card blocks directly to the image file
+    #[arg(long, conflicts_with = "sd_overlay")]
+    sd_direct: bool,
+    /// serve FAT image built from host directory as SD card, instead of image file
+    #[arg(long, conflicts_with_all = ["sd_img_path", "sd_overlay", "sd_direct"])]
+    sd_dir: Option<std::path::PathBuf>,
+    /// write new and changed files from SD card back to --sd-dir directory on exit
+    #[arg(long, requires = "sd_dir")]
+    sd_sync: bool,
+    /// layout of image built with --sd-dir
+    #[command(flatten)]
+    sd_fat: FatArgs,
+    /// probability of flipping a bit in each byte of SD data block transfers (fault injection)
+    #[arg(long)]
+    sd_fault_rate: Option<f64>,
+    /// seed of SD fault injection random generator
+    #[arg(long, default_value_t = 1, requires = "sd_fault_rate")]
+    sd_fault_seed: u64,
+    /// path of SPI flash image file, connected to SPI chip select 1
+    #[arg(long)]
+    spi_flash: Option<std::path::PathBuf>,
+    /// run real-time clock deterministically, starting at given time (unix seconds or `YYYY-MM-DDTHH:MM:SS` UTC)
+    /// and advancing with executed instructions, instead of following host clock
+    #[arg(long, value_parser = parse_rtc_epoch)]
+    rtc_epoch: Option<i64>,
+    /// initial value of GPIO input pins
+    #[arg(long, value_parser = parse_u16, default_value = "0")]
+    gpio_in: u16,
+    /// GPIO input changes to apply during run (`<instructions> <pin> <0|1>` lines)
+    #[arg(long)]
+    gpio_script: Option<std::path::PathBuf>,
+    /// write VGA snapshots to `<prefix><number>.<ext>` files, final picture is written to `<prefix>final.<ext>` on exit
+    #[arg(long)]
+    vga_snapshot: Option<std::path::PathBuf>,
+    /// image format of VGA snapshots
+    #[arg(long, value_enum, default_value_t = ImageFormat::Png)]
+    vga_format: ImageFormat,
+    /// write VGA snapshot every N frames
+    #[arg(long, requires = "vga_snapshot")]
+    vga_every: Option<u64>,
+    /// show VGA text mode screen in a terminal window
+    #[arg(long)]
+    vga_term: bool,
+    /// open terminal window for keyboard input
+    #[arg(long)]
+    kbd_term: bool,
+    /// keyboard events to apply during run (`<instructions> <action>` lines, action is `type "text"`,
+    /// `press <key>`, `release <key>`, `tap <key>` or `raw <byte>...`)
+    #[arg(long)]
+    kbd_script: Option<std::path::PathBuf>,
+    /// read monitor commands from stdin while running (`gpio <pin> <0|1>` sets input pin, `gpio` prints pin states,
+    /// `key <action>` sends keyboard event, `screenshot [<path>]` writes VGA snapshot, `reset` resets the system)
+    #[arg(long)]
+    monitor: bool,
+    /// host directory guest can access files in through sim control and hostfs devices
+    #[arg(long)]
+    host_dir: Option<std::path::PathBuf>,
+    /// capacity of queue of host input events (terminals, monitor), host input waits while it is full
+    /// [default: unbounded]
+    #[arg(long)]
+    host_queue: Option<usize>,
+    /// keep RAM and VRAM contents on system reset instead of clearing them and reloading program images
+    #[arg(long)]
+    reset_keep_ram: bool,
+    /// feed content of file to UART RX instead of opening terminal window
+    #[arg(long)]
+    uart_input: Option<std::path::PathBuf>,
+    /// write UART TX to file instead of opening terminal window (stdout if only --uart-input is given)
+    #[arg(long)]
+    uart_output: Option<std::path::PathBuf>,
+    /// console automation script run against UART (`send "text"`, `expect "text"`, `timeout <instructions>`,
+    /// `wait <instructions>`, `sleep <seconds>` lines)
+    #[arg(long)]
+    uart_script: Option<std::path::PathBuf>,
+    /// log nondeterministic inputs (UART RX, keyboard, GPIO, host time) to file, to be replayed with --replay
+    #[arg(long, conflicts_with = "replay")]
+    record: Option<std::path::PathBuf>,
+    /// reproduce run recorded with --record (other options should match the recorded run)
+    #[arg(long, conflicts_with_all = ["gpio_script", "kbd_script", "uart_script", "monitor"])]
+    replay: Option<std::path::PathBuf>,
+    /// don't print execution trace
+    #[arg(long)]
+    no_trace: bool,
+    /// stop after executing N instructions
+    #[arg(long)]
+    max_instructions: Option<u64>,
+    /// stop after running for given number of seconds (host time)
+    #[arg(long, value_parser = parse_seconds)]
+    timeout: Option<time::Duration>,
+    /// stop when guest jumps to itself with interrupts disabled
+    #[arg(long)]
+    stop_on_halt: bool,
+    /// stop when guest reaches given pc (idle loop)
+    #[arg(long, value_parser = parse_u16)]
+    idle_pc: Option<u16>,
+    /// clock source of the timer device
+    #[arg(long, value_enum, default_value_t = TimerClock::Instructions)]
+    timer_clock: TimerClock,
+}
+
+fn open_term() -> Pty {
+    let pty = Pty::open().expect("Failed to open PTY terminal pair");
+    pty.spawn_term();
+    pty
+}
+
+fn parse_seconds(s: &str) -> Result<time::Duration, String> {
+    s.parse::<f64>().ok()
+        .and_then(|secs| time::Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| format!("`{}` is not a non-negative number of seconds", s))
+}
+
+fn parse_u16(s: &str) -> Result<u16, String> {
+    parse_addr(s).and_then(|v| u16::try_from(v).ok()).ok_or_else(|| format!("`{}` is not a 16-bit number", s))
+}
+
+fn parse_rtc_epoch(s: &str) -> Result<i64, String> {
+    parse_time(s).ok_or_else(|| format!("`{}` is not unix time or YYYY-MM-DDTHH:MM:SS date", s))
 }
 
 fn read_file(path: &std::path::PathBuf) -> Vec<u8> {
     let mut buff = Vec::new();
-    File::open(path).expect(&format!("Failed to open file {}", path.to_str().unwrap()))
-        .read_to_end(&mut buff).expect(&format!("Failed to read file {}", path.to_str().unwrap()));
+    File::open(path).unwrap_or_else(|_| panic!("Failed to open file {}", path.to_str().unwrap()))
+        .read_to_end(&mut buff).unwrap_or_else(|_| panic!("Failed to read file {}", path.to_str().unwrap()));
     buff
 }
 
+fn open_sd_image(path: &std::path::PathBuf, overlay_path: &Option<std::path::PathBuf>, direct: bool) -> DiskImage {
+    if direct {
+        let base = File::options().read(true).write(true).open(path).expect("Failed to open SD image file");
+        return DiskImage::direct(base).expect("Failed to read SD image size");
+    }
+
+    let base = File::open(path).expect("Failed to open SD image file");
+    let overlay = match overlay_path {
+        Some(overlay_path) => {
+            let file = File::options().read(true).write(true).create(true).truncate(false).open(overlay_path)
+                .expect("Failed to open SD overlay file");
+            Overlay::open(file).expect("Failed to load SD overlay file")
+        },
+        None => Overlay::in_memory(),
+    };
+    DiskImage::with_overlay(base, overlay).expect("Failed to read SD image size")
+}
+
+fn load_symbols(path: &Option<std::path::PathBuf>) -> SymbolTable {
+    match path {
+        Some(path) => SymbolTable::load(path)
+            .unwrap_or_else(|e| panic!("Failed to load symbols from {}: {}", path.to_str().unwrap(), e)),
+        None => SymbolTable::new(),
+    }
+}
+
+fn print_disassembly(prog: &[u8], symbols: &SymbolTable) {
+    for (pc, word) in prog.chunks(4).enumerate() {
+        let mut raw = [0; 4];
+        raw[..word.len()].copy_from_slice(word);
+        let instr = u32::from_le_bytes(raw);
+
+        if let Some(label) = symbols.label(pc as u32) {
+            println!("{}:", label);
+        }
+        println!("{:#06x} <{}>: {:#010x}  {}", pc, symbols.format(pc as u32), instr,
+                 disassemble(&Encoding::from_raw(instr), symbols));
+    }
+}
+
 fn main() {
     let args = CliArgs::parse();
 
-    let prog_buff = read_file(&args.prog_bin_path);
-    let data_buff = read_file(&args.data_bin_path);
-    let sd_img = File::open(args.sd_img_path).expect("Failed to open SD image file");
+    match args.command {
+        Some(CliCommand::Disasm { prog_bin_path, symbols }) => {
+            print_disassembly(&read_file(&prog_bin_path), &load_symbols(&symbols));
+            return;
+        },
+        Some(CliCommand::Mkimage { dir, output, fat }) => {
+            let img = fat::build_image(&dir, &fat.options())
+                .unwrap_or_else(|e| panic!("Failed to build image from {}: {}", dir.to_str().unwrap(), e));
+            std::fs::write(&output, img).expect("Failed to write image file");
+            return;
+        },
+        Some(CliCommand::Commit { sd_img_path, overlay_path }) => {
+            let base = File::options().read(true).write(true).open(&sd_img_path).expect("Failed to open SD image file");
+            let overlay = File::options().read(true).write(true).open(&overlay_path).expect("Failed to open SD overlay file");
+            let mut image = DiskImage::with_overlay(base, Overlay::open(overlay).expect("Failed to load SD overlay file"))
+                .expect("Failed to read SD image size");
+            let blocks = image.commit().expect("Failed to commit SD overlay");
+            println!("Committed {} blocks to {}", blocks, sd_img_path.to_str().unwrap());
+            return;
+        },
+        Some(CliCommand::Test { dir, max_instructions, timeout, jobs, update }) => {
+            trace::set_enabled(false);
+            signal::install_interrupt_handler();
+            let opts = TestOptions {
+                max_instructions, timeout, update,
+                jobs: jobs.unwrap_or_else(|| thread::available_parallelism().map(|n| n.get()).unwrap_or(1)),
+            };
+            let passed = run_tests(&dir, &opts)
+                .unwrap_or_else(|e| panic!("Failed to read test directory {}: {}", dir.to_str().unwrap(), e));
+            std::process::exit(if passed { 0 } else { 1 });
+        },
+        None => {},
+    }
+
+    let args = args.run;
+    // required by clap when no subcommand is given
+    let prog_buff = read_file(&args.prog_bin_path.unwrap());
+    let data_buff = read_file(&args.data_bin_path.unwrap());
+    let sd_dir_image = args.sd_dir.as_ref().map(|dir| {
+        let img = fat::build_image(dir, &args.sd_fat.options())
+            .unwrap_or_else(|e| panic!("Failed to build SD image from {}: {}", dir.to_str().unwrap(), e));
+        Rc::new(RefCell::new(img))
+    });
+    let sd_image = match &sd_dir_image {
+        Some(img) => DiskImage::memory(Rc::clone(img)),
+        None => open_sd_image(args.sd_img_path.as_ref().unwrap(), &args.sd_overlay, args.sd_direct),
+    };
+    let sd_fault = args.sd_fault_rate.map(|rate| FaultInjector::new(rate, args.sd_fault_seed));
+    let spi_flash = args.spi_flash.as_ref().map(|path| {
+        let file = File::options().read(true).write(true).open(path).expect("Failed to open SPI flash image file");
+        SpiFlash::new(file).unwrap_or_else(|e| panic!("Failed to load SPI flash image {}: {}", path.display(), e))
+    });
+    let symbols = Rc::new(load_symbols(&args.symbols));
+
+    let mut hooks: Vec<Rc<RefCell<dyn ExecHook>>> = vec![];
+    let profiler = Rc::new(RefCell::new(Profiler::new(Rc::clone(&symbols))));
+    if args.profile.is_some() || args.profile_stacks.is_some() {
+        hooks.push(Rc::clone(&profiler) as Rc<RefCell<dyn ExecHook>>);
+    }
+
+    let coverage = Rc::new(RefCell::new(Coverage::new()));
+    if args.coverage.is_some() || args.coverage_lcov.is_some() || args.coverage_listing.is_some() {
+        hooks.push(Rc::clone(&coverage) as Rc<RefCell<dyn ExecHook>>);
+    }
+    let line_map = args.line_map.as_ref()
+        .map(|path| LineMap::load(path).unwrap_or_else(|e| panic!("Failed to load line map {}: {}", path.to_str().unwrap(), e)));
+
+    signal::install_interrupt_handler();
+    let gpio_script = args.gpio_script.as_ref()
+        .map(|path| GpioScript::load(path).unwrap_or_else(|e| panic!("Failed to load GPIO script {}: {}", path.to_str().unwrap(), e)));
+    let vga_term = args.vga_term.then(open_term);
+    let kbd_term = args.kbd_term.then(open_term);
+    let kbd_script = args.kbd_script.as_ref()
+        .map(|path| KeyboardScript::load(path).unwrap_or_else(|e| panic!("Failed to load keyboard script {}: {}", path.to_str().unwrap(), e)));
+    let host_dir = args.host_dir.as_ref()
+        .map(|dir| Sandbox::new(dir).unwrap_or_else(|e| panic!("Failed to open host directory {}: {}", dir.to_str().unwrap(), e)));
+    let vga_output = FramebufferOutput {
+        snapshot_prefix: args.vga_snapshot.clone(), format: args.vga_format, every_frames: args.vga_every, term: vga_term,
+    };
+    let serial = if args.uart_input.is_some() || args.uart_output.is_some() {
+        let input = args.uart_input.as_ref().map(read_file).unwrap_or_default();
+        let output: Box<dyn Write> = match &args.uart_output {
+            Some(path) => Box::new(BufWriter::new(File::create(path).expect("Failed to create UART output file"))),
+            None => Box::new(std::io::stdout()),
+        };
+        SerialHost::Headless { input: input.into(), output }
+    } else {
+        SerialHost::Pty(Pty::open().expect("Failed to open PTY terminal pair"))
+    };
+    let uart_script = args.uart_script.as_ref()
+        .map(|path| UartScript::load(path).unwrap_or_else(|e| panic!("Failed to load UART script {}: {}", path.to_str().unwrap(), e)));
+    let input_log = match (&args.record, &args.replay) {
+        (Some(path), _) => InputLog::record(File::create(path).expect("Failed to create input log file")),
+        (_, Some(path)) => InputLog::replay(path)
+            .unwrap_or_else(|e| panic!("Failed to load input log {}: {}", path.to_str().unwrap(), e)),
+        (None, None) => InputLog::live(),
+    };
+    trace::set_enabled(!args.no_trace);
+    let config = SystemConfig {
+        serial, uart_script,
+        sd_image, sd_fault, spi_flash, timer_clock: args.timer_clock,
+        rtc_source: args.rtc_epoch.map(|epoch| RtcSource::Deterministic { epoch }).unwrap_or(RtcSource::Host),
+        gpio_in: args.gpio_in, gpio_script, vga_output, kbd_term, kbd_script, monitor: args.monitor,
+        host_queue: args.host_queue, input_log,
+        host_dir, reset_keep_ram: args.reset_keep_ram,
+        limits: RunLimits {
+            max_instructions: args.max_instructions, timeout: args.timeout,
+            idle_pc: args.idle_pc, stop_on_halt: args.stop_on_halt,
+        },
+    };
+    let result = build_system(&prog_buff, &data_buff, config, Rc::clone(&symbols), hooks);
+    println!("{} after {} instructions", result.stop.describe(), result.instructions);
+    println!("{}", result.cpu_state);
+
+    if let (Some(dir), Some(img), true) = (&args.sd_dir, &sd_dir_image, args.sd_sync) {
+        // guest may have corrupted the filesystem, files synced before the error are kept
+        match fat::sync_to_dir(&img.borrow(), dir) {
+            Ok(written) => println!("Synced {} files to {}", written, dir.to_str().unwrap()),
+            Err(e) => println!("Failed to sync SD image to directory {}: {}", dir.to_str().unwrap(), e),
+        }
+    }
+
+    if let Some(path) = &args.profile {
+        profiler.borrow().write_report(path).expect("Failed to write profile");
+    }
+    if let Some(path) = &args.profile_stacks {
+        profiler.borrow().write_collapsed_stacks(path).expect("Failed to write profile stacks");
+    }
+
+    let mut coverage = coverage.borrow_mut();
+    if let Some(path) = &args.coverage {
+        coverage.merge_file(path).expect("Failed to read previous coverage data");
+        coverage.write_data(path).expect("Failed to write coverage data");
+    }
+    if let Some(path) = &args.coverage_lcov {
+        coverage.write_lcov(path, line_map.as_ref().unwrap()).expect("Failed to write lcov coverage");
+    }
+    if let Some(path) = &args.coverage_listing {
+        coverage.write_listing(path, &prog_buff, &symbols, line_map.as_ref()).expect("Failed to write coverage listing");
+    }
 
-    build_system(&prog_buff, &data_buff, sd_img);
+    std::process::exit(result.stop.exit_code());
 }
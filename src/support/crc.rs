@@ -0,0 +1,57 @@
+// Bitwise CRC implementations used by emulated peripherals and file formats. Speed is not
+// a concern here, blocks are small and CRCs are computed once per transfer.
+
+/// CRC7 (polynomial x^7 + x^3 + 1) used by SD commands, returns 7-bit value
+pub fn crc7(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+    for byte in data {
+        for bit in (0..8).rev() {
+            let in_bit = (byte >> bit) & 1;
+            let msb = (crc >> 6) & 1;
+            crc = (crc << 1) & 0x7f;
+            if in_bit ^ msb != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
+
+/// CRC16-CCITT (polynomial x^16 + x^12 + x^5 + 1, initial value 0) used by SD data blocks
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// CRC32 (IEEE 802.3, reflected), as used by PNG chunks
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_vectors() {
+        // command frames end with CRC7 shifted left and end bit set
+        assert_eq!((crc7(&[0x40, 0x00, 0x00, 0x00, 0x00]) << 1) | 1, 0x95); // CMD0
+        assert_eq!((crc7(&[0x48, 0x00, 0x00, 0x01, 0xaa]) << 1) | 1, 0x87); // CMD8
+        assert_eq!(crc16(&[0xff; 512]), 0x7fa1);
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
@@ -0,0 +1,68 @@
+// Line diff for test reports, longest common subsequence based. Quadratic, but compared outputs
+// are console logs of test programs.
+
+const CONTEXT: usize = 3;
+
+enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Changed lines prefixed with `-` (expected) and `+` (actual), with a few lines of context
+pub fn line_diff(expected: &str, actual: &str) -> String {
+    let a: Vec<_> = expected.lines().collect();
+    let b: Vec<_> = actual.lines().collect();
+
+    // lcs[i][j] - length of common subsequence of a[i..] and b[j..]
+    let mut lcs = vec![vec![0_usize; b.len()+1]; a.len()+1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] { lcs[i+1][j+1] + 1 } else { lcs[i+1][j].max(lcs[i][j+1]) };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            lines.push(Line::Same(a[i]));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i+1][j] >= lcs[i][j+1]) {
+            lines.push(Line::Removed(a[i]));
+            i += 1;
+        } else {
+            lines.push(Line::Added(b[j]));
+            j += 1;
+        }
+    }
+
+    let changed: Vec<_> = lines.iter().enumerate()
+        .filter(|(_, line)| !matches!(line, Line::Same(_)))
+        .map(|(pos, _)| pos)
+        .collect();
+    let near_change = |pos: usize| changed.iter().any(|c| c.abs_diff(pos) <= CONTEXT);
+
+    let mut out = String::new();
+    let mut skipped = false;
+    for (pos, line) in lines.iter().enumerate() {
+        if !near_change(pos) {
+            skipped = true;
+            continue;
+        }
+        if skipped {
+            out.push_str("  ...\n");
+            skipped = false;
+        }
+        match line {
+            Line::Same(text) => out.push_str(&format!("  {}\n", text)),
+            Line::Removed(text) => out.push_str(&format!("- {}\n", text)),
+            Line::Added(text) => out.push_str(&format!("+ {}\n", text)),
+        }
+    }
+    if skipped && !out.is_empty() {
+        out.push_str("  ...\n");
+    }
+    out
+}
@@ -0,0 +1,62 @@
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+
+// Queue of events coming from host side threads (terminal readers, monitor stdin). Devices are
+// owned by the simulation thread, host threads only get an EventSender and the simulation loop
+// drains the queue between instructions, where inputs can also be recorded for replay.
+// Queue is unbounded by default. With capacity set, host threads block while it is full, so input
+// is delayed, never dropped.
+
+pub enum HostEvent {
+    /// bytes typed in UART terminal
+    Serial(Vec<u8>),
+    /// bytes typed in keyboard terminal (translated to scan codes by keyboard device)
+    KeyboardTerm(Vec<u8>),
+    /// line of monitor command
+    Monitor(String),
+}
+
+pub struct HostEvents {
+    sender: EventSender,
+    receiver: Receiver<HostEvent>,
+}
+
+#[derive(Clone)]
+pub enum EventSender {
+    Unbounded(Sender<HostEvent>),
+    Bounded(SyncSender<HostEvent>),
+}
+
+impl HostEvents {
+    pub fn new(capacity: Option<usize>) -> HostEvents {
+        let (sender, receiver) = match capacity {
+            Some(capacity) => {
+                let (tx, rx) = mpsc::sync_channel(capacity);
+                (EventSender::Bounded(tx), rx)
+            },
+            None => {
+                let (tx, rx) = mpsc::channel();
+                (EventSender::Unbounded(tx), rx)
+            },
+        };
+        HostEvents { sender, receiver }
+    }
+
+    pub fn sender(&self) -> EventSender {
+        self.sender.clone()
+    }
+
+    /// Events queued since previous call, called between instructions
+    pub fn drain(&self) -> Vec<HostEvent> {
+        self.receiver.try_iter().collect()
+    }
+}
+
+impl EventSender {
+    /// Queue event, blocks while bounded queue is full. Returns false when simulation has ended.
+    pub fn send(&self, event: HostEvent) -> bool {
+        match self {
+            EventSender::Unbounded(tx) => tx.send(event).is_ok(),
+            EventSender::Bounded(tx) => tx.send(event).is_ok(),
+        }
+    }
+}
@@ -0,0 +1,705 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::support::time::{civil_from_days, SECS_PER_DAY};
+
+// FAT16/FAT32 images built from host directory, and syncing files back from such image.
+// Layout follows what card formatting tools produce: MBR with one LBA partition aligned to 1 MiB
+// (unless disabled), two FAT copies, 512 byte sectors and the smallest cluster size that is valid for
+// the FAT type. Files are stored in contiguous cluster chains, names that don't fit 8.3 get long
+// file name entries.
+
+const SECTOR_SIZE: usize = 512;
+const PARTITION_START: u32 = 2048;
+const DIR_ENTRY_SIZE: usize = 32;
+const FAT16_ROOT_ENTRIES: u32 = 512;
+const NUM_FATS: u32 = 2;
+const MAX_DIR_DEPTH: usize = 64; // when syncing back, deeper nesting is treated as directory loop
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+const NTRES_BASE_LOWER: u8 = 0x08;
+const NTRES_EXT_LOWER: u8 = 0x10;
+
+const ENTRY_FREE: u8 = 0xe5;
+const LFN_LAST: u8 = 0x40;
+const LFN_CHARS: usize = 13;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum FatType {
+    Fat16,
+    Fat32,
+}
+
+pub struct ImageOptions {
+    pub size: u64,
+    pub fat_type: FatType,
+    pub partition_table: bool,
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+struct Layout {
+    fat_type: FatType,
+    volume_start: u32,
+    volume_sectors: u32,
+    sectors_per_cluster: u32,
+    reserved_sectors: u32,
+    fat_sectors: u32,
+    root_dir_sectors: u32,
+    clusters: u32,
+}
+
+impl Layout {
+    fn new(opts: &ImageOptions) -> io::Result<Layout> {
+        let total_sectors = (opts.size / SECTOR_SIZE as u64).min(u32::MAX as u64) as u32;
+        let volume_start = if opts.partition_table { PARTITION_START } else { 0 };
+        let volume_sectors = total_sectors.saturating_sub(volume_start);
+
+        let (reserved_sectors, root_dir_sectors, entry_size, min_clusters, max_clusters) = match opts.fat_type {
+            FatType::Fat16 => (1, FAT16_ROOT_ENTRIES * DIR_ENTRY_SIZE as u32 / SECTOR_SIZE as u32, 2, 4085, 65524),
+            FatType::Fat32 => (32, 0, 4, 65525, 0x0fff_fff4),
+        };
+
+        let mut sectors_per_cluster = 1;
+        while sectors_per_cluster <= 128 {
+            let data_sectors = volume_sectors.saturating_sub(reserved_sectors + root_dir_sectors);
+            // FAT size depends on cluster count, which depends on FAT size; estimate from all data sectors
+            let estimate = data_sectors / sectors_per_cluster;
+            let fat_sectors = ((estimate + 2) * entry_size).div_ceil(SECTOR_SIZE as u32);
+            let clusters = data_sectors.saturating_sub(NUM_FATS * fat_sectors) / sectors_per_cluster;
+
+            if clusters < min_clusters {
+                return Err(invalid_data(format!("image of {} sectors is too small for {:?}", total_sectors, opts.fat_type)));
+            }
+            if clusters <= max_clusters {
+                return Ok(Layout {
+                    fat_type: opts.fat_type, volume_start, volume_sectors, sectors_per_cluster,
+                    reserved_sectors, fat_sectors, root_dir_sectors, clusters,
+                });
+            }
+            sectors_per_cluster *= 2;
+        }
+        Err(invalid_data(format!("image of {} sectors is too large for {:?}", total_sectors, opts.fat_type)))
+    }
+
+    fn fat_start(&self) -> u32 {
+        self.volume_start + self.reserved_sectors
+    }
+
+    fn root_dir_start(&self) -> u32 {
+        self.fat_start() + NUM_FATS * self.fat_sectors
+    }
+
+    fn cluster_bytes(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> usize {
+        let data_start = self.root_dir_start() + self.root_dir_sectors;
+        (data_start as usize + (cluster as usize - 2) * self.sectors_per_cluster as usize) * SECTOR_SIZE
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff,
+        }
+    }
+}
+
+/// Build image of given size with content of host directory
+pub fn build_image(dir: &Path, opts: &ImageOptions) -> io::Result<Vec<u8>> {
+    let layout = Layout::new(opts)?;
+    let mut builder = Builder {
+        img: vec![0; opts.size as usize / SECTOR_SIZE * SECTOR_SIZE],
+        fat: vec![0; layout.clusters as usize + 2],
+        next_cluster: 2,
+        layout,
+    };
+    builder.fat[0] = 0x0fff_ff00 | 0xf8; // media descriptor
+    builder.fat[1] = builder.layout.end_of_chain();
+
+    let root_cluster = builder.add_dir(dir, None)?;
+    builder.finish(root_cluster);
+    Ok(builder.img)
+}
+
+struct Builder {
+    img: Vec<u8>,
+    layout: Layout,
+    fat: Vec<u32>,
+    next_cluster: u32,
+}
+
+struct NewEntry {
+    short_name: [u8; 11],
+    ntres: u8,
+    long_name: Option<String>,
+    host_path: std::path::PathBuf,
+    is_dir: bool,
+    modified: (u16, u16),
+}
+
+impl Builder {
+    /// Allocate contiguous cluster chain, returns first cluster (0 for empty chain)
+    fn alloc(&mut self, bytes: usize) -> io::Result<u32> {
+        let count = bytes.div_ceil(self.layout.cluster_bytes()) as u32;
+        if count == 0 {
+            return Ok(0);
+        }
+        let first = self.next_cluster;
+        if (first + count) as usize > self.fat.len() {
+            return Err(io::Error::new(io::ErrorKind::OutOfMemory, "directory content does not fit in image"));
+        }
+        for cluster in first..first+count-1 {
+            self.fat[cluster as usize] = cluster + 1;
+        }
+        self.fat[(first+count-1) as usize] = self.layout.end_of_chain();
+        self.next_cluster += count;
+        Ok(first)
+    }
+
+    /// Add directory content, returns cluster of directory (None for root directory)
+    fn add_dir(&mut self, host_dir: &Path, parent: Option<u32>) -> io::Result<u32> {
+        let mut host_entries: Vec<_> = fs::read_dir(host_dir)?.collect::<io::Result<_>>()?;
+        host_entries.sort_by_key(|e| e.file_name());
+
+        let mut used_names = HashSet::new();
+        let mut entries = Vec::new();
+        for host_entry in host_entries {
+            let name = host_entry.file_name().to_string_lossy().into_owned();
+            let meta = host_entry.metadata()?;
+            if !meta.is_dir() && !meta.is_file() {
+                continue;
+            }
+            let (short_name, ntres, long_name) = short_name(&name, &mut used_names);
+            let modified = meta.modified().map(fat_timestamp).unwrap_or((0, 0));
+            entries.push(NewEntry { short_name, ntres, long_name, host_path: host_entry.path(), is_dir: meta.is_dir(), modified });
+        }
+
+        let is_root = parent.is_none();
+        let entry_count = (if is_root { 0 } else { 2 }) + entries.iter().map(|e| 1 + lfn_entry_count(&e.long_name)).sum::<usize>();
+        let dir_bytes = entry_count * DIR_ENTRY_SIZE;
+
+        let (dir_cluster, dir_offset) = if is_root && self.layout.fat_type == FatType::Fat16 {
+            if entry_count > FAT16_ROOT_ENTRIES as usize {
+                return Err(invalid_data(format!("too many entries in root directory ({}, max {})", entry_count, FAT16_ROOT_ENTRIES)));
+            }
+            (0, self.layout.root_dir_start() as usize * SECTOR_SIZE)
+        } else {
+            let cluster = self.alloc(dir_bytes.max(1))?;
+            (cluster, self.layout.cluster_offset(cluster))
+        };
+
+        let mut dir = Vec::with_capacity(dir_bytes);
+        if let Some(parent) = parent {
+            let (time, date) = (0, fat_date(1980, 1, 1));
+            dir.extend(dir_entry(b".          ", ATTR_DIRECTORY, 0, dir_cluster, 0, (time, date)));
+            dir.extend(dir_entry(b"..         ", ATTR_DIRECTORY, 0, parent, 0, (time, date)));
+        }
+
+        // parent of top level directories is referenced as cluster 0, also on FAT32
+        let self_ref = if is_root { 0 } else { dir_cluster };
+        for entry in entries {
+            let (attr, cluster, size) = if entry.is_dir {
+                (ATTR_DIRECTORY, self.add_dir(&entry.host_path, Some(self_ref))?, 0)
+            } else {
+                let data = fs::read(&entry.host_path)?;
+                let cluster = self.alloc(data.len())?;
+                if cluster != 0 {
+                    let offset = self.layout.cluster_offset(cluster);
+                    self.img[offset..offset+data.len()].copy_from_slice(&data);
+                }
+                (ATTR_ARCHIVE, cluster, data.len() as u32)
+            };
+
+            if let Some(long_name) = &entry.long_name {
+                dir.extend(lfn_entries(long_name, &entry.short_name));
+            }
+            dir.extend(dir_entry(&entry.short_name, attr, entry.ntres, cluster, size, entry.modified));
+        }
+
+        self.img[dir_offset..dir_offset+dir.len()].copy_from_slice(&dir);
+        Ok(dir_cluster)
+    }
+
+    fn finish(&mut self, root_cluster: u32) {
+        let layout = &self.layout;
+        let boot_offset = layout.volume_start as usize * SECTOR_SIZE;
+        let boot = &mut self.img[boot_offset..boot_offset+SECTOR_SIZE];
+
+        // jump over BPB to boot code
+        boot[0..3].copy_from_slice(&[0xeb, if layout.fat_type == FatType::Fat32 { 0x58 } else { 0x3c }, 0x90]);
+        boot[3..11].copy_from_slice(b"PCSN    ");
+        boot[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+        boot[13] = layout.sectors_per_cluster as u8;
+        boot[14..16].copy_from_slice(&(layout.reserved_sectors as u16).to_le_bytes());
+        boot[16] = NUM_FATS as u8;
+        boot[21] = 0xf8; // fixed media
+        boot[24..26].copy_from_slice(&63_u16.to_le_bytes()); // sectors per track
+        boot[26..28].copy_from_slice(&255_u16.to_le_bytes()); // heads
+        boot[28..32].copy_from_slice(&layout.volume_start.to_le_bytes()); // hidden sectors
+        if layout.volume_sectors < 0x10000 {
+            boot[19..21].copy_from_slice(&(layout.volume_sectors as u16).to_le_bytes());
+        } else {
+            boot[32..36].copy_from_slice(&layout.volume_sectors.to_le_bytes());
+        }
+
+        let ext_offset = match layout.fat_type {
+            FatType::Fat16 => {
+                boot[17..19].copy_from_slice(&(FAT16_ROOT_ENTRIES as u16).to_le_bytes());
+                boot[22..24].copy_from_slice(&(layout.fat_sectors as u16).to_le_bytes());
+                36
+            },
+            FatType::Fat32 => {
+                boot[36..40].copy_from_slice(&layout.fat_sectors.to_le_bytes());
+                boot[44..48].copy_from_slice(&root_cluster.to_le_bytes());
+                boot[48..50].copy_from_slice(&1_u16.to_le_bytes()); // fsinfo sector
+                boot[50..52].copy_from_slice(&6_u16.to_le_bytes()); // backup boot sector
+                64
+            },
+        };
+        boot[ext_offset] = 0x80; // drive number
+        boot[ext_offset+2] = 0x29; // extended boot signature
+        boot[ext_offset+3..ext_offset+7].copy_from_slice(&0x5053_434e_u32.to_le_bytes()); // volume id
+        boot[ext_offset+7..ext_offset+18].copy_from_slice(b"NO NAME    ");
+        boot[ext_offset+18..ext_offset+26].copy_from_slice(match layout.fat_type {
+            FatType::Fat16 => b"FAT16   ",
+            FatType::Fat32 => b"FAT32   ",
+        });
+        boot[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+        if layout.fat_type == FatType::Fat32 {
+            let free = layout.clusters + 2 - self.next_cluster;
+            let fsinfo = &mut self.img[boot_offset+SECTOR_SIZE..boot_offset+2*SECTOR_SIZE];
+            fsinfo[0..4].copy_from_slice(&0x4161_5252_u32.to_le_bytes());
+            fsinfo[484..488].copy_from_slice(&0x6141_7272_u32.to_le_bytes());
+            fsinfo[488..492].copy_from_slice(&free.to_le_bytes());
+            fsinfo[492..496].copy_from_slice(&self.next_cluster.to_le_bytes());
+            fsinfo[508..512].copy_from_slice(&0xaa55_0000_u32.to_le_bytes());
+
+            self.img.copy_within(boot_offset..boot_offset+2*SECTOR_SIZE, boot_offset+6*SECTOR_SIZE);
+        }
+
+        let fat: Vec<u8> = match layout.fat_type {
+            FatType::Fat16 => self.fat.iter().flat_map(|e| (*e as u16).to_le_bytes()).collect(),
+            FatType::Fat32 => self.fat.iter().flat_map(|e| e.to_le_bytes()).collect(),
+        };
+        for i in 0..NUM_FATS {
+            let offset = (layout.fat_start() + i * layout.fat_sectors) as usize * SECTOR_SIZE;
+            self.img[offset..offset+fat.len()].copy_from_slice(&fat);
+        }
+
+        if layout.volume_start != 0 {
+            let entry = &mut self.img[446..462];
+            entry[1..4].copy_from_slice(&[0xfe, 0xff, 0xff]); // CHS not used
+            entry[4] = match layout.fat_type {
+                FatType::Fat16 => 0x0e,
+                FatType::Fat32 => 0x0c,
+            };
+            entry[5..8].copy_from_slice(&[0xfe, 0xff, 0xff]);
+            entry[8..12].copy_from_slice(&layout.volume_start.to_le_bytes());
+            entry[12..16].copy_from_slice(&layout.volume_sectors.to_le_bytes());
+            self.img[510..512].copy_from_slice(&[0x55, 0xaa]);
+        }
+    }
+}
+
+fn dir_entry(name: &[u8; 11], attr: u8, ntres: u8, cluster: u32, size: u32, (time, date): (u16, u16)) -> [u8; DIR_ENTRY_SIZE] {
+    let mut entry = [0; DIR_ENTRY_SIZE];
+    entry[0..11].copy_from_slice(name);
+    entry[11] = attr;
+    entry[12] = ntres;
+    entry[14..16].copy_from_slice(&time.to_le_bytes());
+    entry[16..18].copy_from_slice(&date.to_le_bytes());
+    entry[18..20].copy_from_slice(&date.to_le_bytes());
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[22..24].copy_from_slice(&time.to_le_bytes());
+    entry[24..26].copy_from_slice(&date.to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+fn short_name_char_valid(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+/// Returns 8.3 name with case flags, and long name if it can't be represented by short name alone
+fn short_name(name: &str, used: &mut HashSet<[u8; 11]>) -> ([u8; 11], u8, Option<String>) {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot+1..]),
+        _ => (name, ""),
+    };
+
+    let mut short = [b' '; 11];
+    let uniform_case = |s: &str| s == s.to_uppercase() || s == s.to_lowercase();
+    let fits = (1..=8).contains(&base.len()) && ext.len() <= 3
+        && base.chars().chain(ext.chars()).all(short_name_char_valid)
+        && uniform_case(base) && uniform_case(ext);
+    if fits {
+        short[..base.len()].copy_from_slice(base.to_uppercase().as_bytes());
+        short[8..8+ext.len()].copy_from_slice(ext.to_uppercase().as_bytes());
+        if used.insert(short) {
+            let mut ntres = 0;
+            if base.chars().any(|c| c.is_ascii_lowercase()) {
+                ntres |= NTRES_BASE_LOWER;
+            }
+            if ext.chars().any(|c| c.is_ascii_lowercase()) {
+                ntres |= NTRES_EXT_LOWER;
+            }
+            return (short, ntres, None);
+        }
+    }
+
+    let filter = |s: &str, len: usize| -> Vec<u8> {
+        s.chars().filter(|c| short_name_char_valid(*c)).map(|c| c.to_ascii_uppercase() as u8).take(len).collect()
+    };
+    let base_chars = filter(base, 6);
+    let ext_chars = filter(ext, 3);
+    for n in 1.. {
+        let tail = format!("~{}", n);
+        let base_len = base_chars.len().min(8 - tail.len());
+        let mut short = [b' '; 11];
+        short[..base_len].copy_from_slice(&base_chars[..base_len]);
+        short[base_len..base_len+tail.len()].copy_from_slice(tail.as_bytes());
+        short[8..8+ext_chars.len()].copy_from_slice(&ext_chars);
+        if used.insert(short) {
+            return (short, 0, Some(name.to_string()));
+        }
+    }
+    unreachable!()
+}
+
+fn lfn_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0_u8, |sum, c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(*c))
+}
+
+fn lfn_entry_count(long_name: &Option<String>) -> usize {
+    long_name.as_ref().map(|n| n.encode_utf16().count().div_ceil(LFN_CHARS)).unwrap_or(0)
+}
+
+// offsets of UCS-2 characters within long name entry
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Long name entries in on-disk order (last part first)
+fn lfn_entries(long_name: &str, short_name: &[u8; 11]) -> Vec<u8> {
+    let mut chars: Vec<u16> = long_name.encode_utf16().collect();
+    let count = chars.len().div_ceil(LFN_CHARS);
+    if !chars.len().is_multiple_of(LFN_CHARS) {
+        chars.push(0);
+    }
+    chars.resize(count * LFN_CHARS, 0xffff);
+
+    let checksum = lfn_checksum(short_name);
+    let mut entries = Vec::with_capacity(count * DIR_ENTRY_SIZE);
+    for seq in (1..=count).rev() {
+        let mut entry = [0; DIR_ENTRY_SIZE];
+        entry[0] = seq as u8 | if seq == count { LFN_LAST } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+        for (i, offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+            entry[*offset..offset+2].copy_from_slice(&chars[(seq-1)*LFN_CHARS + i].to_le_bytes());
+        }
+        entries.extend(entry);
+    }
+    entries
+}
+
+fn fat_date(year: i64, month: u32, day: u32) -> u16 {
+    (((year - 1980) as u16) << 9) | ((month as u16) << 5) | day as u16
+}
+
+/// Convert host time to FAT (time, date) pair, times before 1980 are clamped to FAT epoch
+fn fat_timestamp(time: std::time::SystemTime) -> (u16, u16) {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(SECS_PER_DAY));
+    if year < 1980 {
+        return (0, fat_date(1980, 1, 1));
+    }
+    let day_secs = secs.rem_euclid(SECS_PER_DAY);
+    let time = ((day_secs / 3600) << 11) | (((day_secs / 60) % 60) << 5) | ((day_secs % 60) / 2);
+    (time as u16, fat_date(year.min(2107), month, day))
+}
+
+/// Write files from image back to host directory. Only new and changed files are written,
+/// files deleted by the guest are kept on the host. Returns number of written files.
+pub fn sync_to_dir(img: &[u8], dir: &Path) -> io::Result<usize> {
+    let volume = Volume::open(img)?;
+    let root = volume.root_dir()?;
+    volume.sync_dir(&root, dir, 0)
+}
+
+struct Volume<'a> {
+    img: &'a [u8],
+    fat_type: FatType,
+    sectors_per_cluster: usize,
+    fat_offset: usize,
+    root_dir_offset: usize,
+    root_dir_sectors: usize,
+    data_offset: usize,
+    root_cluster: u32,
+    clusters: u32,
+}
+
+struct DirEntry {
+    name: String,
+    is_dir: bool,
+    cluster: u32,
+    size: u32,
+}
+
+impl Volume<'_> {
+    fn open(img: &[u8]) -> io::Result<Volume<'_>> {
+        let sector = |n: usize| img.get(n*SECTOR_SIZE..(n+1)*SECTOR_SIZE).ok_or_else(|| invalid_data("image is truncated".to_string()));
+        let u16_at = |s: &[u8], at: usize| u16::from_le_bytes([s[at], s[at+1]]) as usize;
+        let u32_at = |s: &[u8], at: usize| u32::from_le_bytes(s[at..at+4].try_into().unwrap()) as usize;
+
+        // boot sector starts with jump instruction, otherwise it is MBR and volume is in first partition
+        let mbr = sector(0)?;
+        let start = if matches!(mbr[0], 0xeb | 0xe9) && u16_at(mbr, 11) == SECTOR_SIZE { 0 } else { u32_at(mbr, 446+8) };
+        let boot = sector(start)?;
+        if u16_at(boot, 11) != SECTOR_SIZE || boot[13] == 0 || boot[510..512] != [0x55, 0xaa] {
+            return Err(invalid_data("no FAT filesystem found in image".to_string()));
+        }
+
+        let sectors_per_cluster = boot[13] as usize;
+        let reserved = u16_at(boot, 14);
+        let num_fats = boot[16] as usize;
+        let root_entries = u16_at(boot, 17);
+        let fat_sectors = match u16_at(boot, 22) { 0 => u32_at(boot, 36), n => n };
+        let total_sectors = match u16_at(boot, 19) { 0 => u32_at(boot, 32), n => n };
+
+        let root_dir_sectors = (root_entries * DIR_ENTRY_SIZE).div_ceil(SECTOR_SIZE);
+        let data_start = reserved + num_fats * fat_sectors + root_dir_sectors;
+        let clusters = (total_sectors.saturating_sub(data_start) / sectors_per_cluster) as u32;
+        // type is determined only by cluster count
+        let fat_type = if clusters < 65525 { FatType::Fat16 } else { FatType::Fat32 };
+
+        Ok(Volume {
+            img, fat_type, sectors_per_cluster,
+            fat_offset: (start + reserved) * SECTOR_SIZE,
+            root_dir_offset: (start + reserved + num_fats * fat_sectors) * SECTOR_SIZE,
+            root_dir_sectors,
+            data_offset: (start + data_start) * SECTOR_SIZE,
+            root_cluster: if fat_type == FatType::Fat32 { u32_at(boot, 44) as u32 } else { 0 },
+            clusters,
+        })
+    }
+
+    // offsets come from image written by guest, so every access is checked
+    fn fat_entry(&self, cluster: u32) -> io::Result<u32> {
+        let width = match self.fat_type { FatType::Fat16 => 2, FatType::Fat32 => 4 };
+        let at = self.fat_offset + cluster as usize * width;
+        let raw = self.img.get(at..at+width)
+            .ok_or_else(|| invalid_data(format!("FAT entry of cluster {} is outside of image", cluster)))?;
+        Ok(match self.fat_type {
+            FatType::Fat16 => u16::from_le_bytes([raw[0], raw[1]]) as u32,
+            FatType::Fat32 => u32::from_le_bytes(raw.try_into().unwrap()) & 0x0fff_ffff,
+        })
+    }
+
+    fn read_chain(&self, first: u32) -> io::Result<Vec<u8>> {
+        let cluster_bytes = self.sectors_per_cluster * SECTOR_SIZE;
+        let mut data = Vec::new();
+        let mut cluster = first;
+        while (2..self.clusters + 2).contains(&cluster) {
+            if data.len() >= self.clusters as usize * cluster_bytes {
+                return Err(invalid_data(format!("cluster chain starting at {} has a loop", first)));
+            }
+            let at = self.data_offset + (cluster as usize - 2) * cluster_bytes;
+            data.extend(self.img.get(at..at+cluster_bytes).ok_or_else(|| invalid_data("image is truncated".to_string()))?);
+            cluster = self.fat_entry(cluster)?;
+        }
+        Ok(data)
+    }
+
+    fn root_dir(&self) -> io::Result<Vec<DirEntry>> {
+        if self.fat_type == FatType::Fat16 {
+            let region = self.img.get(self.root_dir_offset..self.root_dir_offset + self.root_dir_sectors*SECTOR_SIZE)
+                .ok_or_else(|| invalid_data("root directory is outside of image".to_string()))?;
+            Ok(parse_dir(region, self.fat_type))
+        } else {
+            Ok(parse_dir(&self.read_chain(self.root_cluster)?, self.fat_type))
+        }
+    }
+
+    fn sync_dir(&self, entries: &[DirEntry], host_dir: &Path, depth: usize) -> io::Result<usize> {
+        // corrupted directory can point to its ancestor
+        if depth > MAX_DIR_DEPTH {
+            return Err(invalid_data(format!("directory {} is nested too deep", host_dir.display())));
+        }
+        fs::create_dir_all(host_dir)?;
+        let mut written = 0;
+        for entry in entries {
+            let host_path = host_dir.join(&entry.name);
+            if entry.is_dir {
+                written += self.sync_dir(&parse_dir(&self.read_chain(entry.cluster)?, self.fat_type), &host_path, depth+1)?;
+                continue;
+            }
+
+            let mut data = self.read_chain(entry.cluster)?;
+            if data.len() < entry.size as usize {
+                return Err(invalid_data(format!("file {} is shorter than its size", host_path.display())));
+            }
+            data.truncate(entry.size as usize);
+            if fs::read(&host_path).ok().as_ref() != Some(&data) {
+                fs::write(&host_path, &data)?;
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
+}
+
+fn parse_dir(dir: &[u8], fat_type: FatType) -> Vec<DirEntry> {
+    let mut entries = Vec::new();
+    let mut long_name: Vec<u16> = Vec::new();
+    let mut long_checksum = None;
+
+    for raw in dir.chunks_exact(DIR_ENTRY_SIZE) {
+        match raw[0] {
+            0x00 => break,
+            ENTRY_FREE => { long_checksum = None; continue; },
+            _ => {},
+        }
+        if raw[11] & 0x3f == ATTR_LONG_NAME {
+            if raw[0] & LFN_LAST != 0 {
+                long_name.clear();
+            }
+            let part: Vec<u16> = LFN_CHAR_OFFSETS.iter().map(|o| u16::from_le_bytes([raw[*o], raw[o+1]])).collect();
+            long_name.splice(0..0, part);
+            long_checksum = Some(raw[13]);
+            continue;
+        }
+        if raw[11] & ATTR_VOLUME_ID != 0 {
+            long_checksum = None;
+            continue;
+        }
+
+        let short: [u8; 11] = raw[0..11].try_into().unwrap();
+        let name = if long_checksum == Some(lfn_checksum(&short)) {
+            let end = long_name.iter().position(|c| *c == 0 || *c == 0xffff).unwrap_or(long_name.len());
+            String::from_utf16_lossy(&long_name[..end])
+        } else {
+            let case = |s: &[u8], lower: bool| {
+                let s = String::from_utf8_lossy(s).trim_end().to_string();
+                if lower { s.to_lowercase() } else { s }
+            };
+            let base = case(&short[..8], raw[12] & NTRES_BASE_LOWER != 0);
+            let ext = case(&short[8..], raw[12] & NTRES_EXT_LOWER != 0);
+            if ext.is_empty() { base } else { format!("{}.{}", base, ext) }
+        };
+        long_checksum = None;
+
+        // names from guest are not trusted to stay within synced directory
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+            continue;
+        }
+        let cluster_hi = if fat_type == FatType::Fat32 { u16::from_le_bytes([raw[20], raw[21]]) as u32 } else { 0 };
+        let cluster = (cluster_hi << 16) | u16::from_le_bytes([raw[26], raw[27]]) as u32;
+        let size = u32::from_le_bytes(raw[28..32].try_into().unwrap());
+        entries.push(DirEntry { name, is_dir: raw[11] & ATTR_DIRECTORY != 0, cluster, size });
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pcsn-fat-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn make_tree(root: &Path) {
+        fs::create_dir_all(root.join("sub/nested")).unwrap();
+        fs::write(root.join("a.txt"), b"hello").unwrap();
+        fs::write(root.join("readme.md"), b"lower case name").unwrap();
+        fs::write(root.join("Long File Name.data"), (0..3000).map(|i| i as u8).collect::<Vec<_>>()).unwrap();
+        fs::write(root.join("sub/nested/deep.bin"), vec![0xa5; 1500]).unwrap();
+        fs::write(root.join("sub/empty"), b"").unwrap();
+    }
+
+    fn tree(root: &Path) -> Vec<(PathBuf, Option<Vec<u8>>)> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(root).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                entries.push((path.strip_prefix(root).unwrap().to_path_buf(), None));
+                entries.extend(tree(&path).into_iter().map(|(p, d)| (path.strip_prefix(root).unwrap().join(p), d)));
+            } else {
+                entries.push((path.strip_prefix(root).unwrap().to_path_buf(), Some(fs::read(&path).unwrap())));
+            }
+        }
+        entries.sort();
+        entries
+    }
+
+    fn round_trip(name: &str, opts: &ImageOptions) {
+        let base = temp_dir(name);
+        make_tree(&base.join("in"));
+        let img = build_image(&base.join("in"), opts).unwrap();
+        assert_eq!(Volume::open(&img).unwrap().fat_type, opts.fat_type);
+
+        assert_eq!(sync_to_dir(&img, &base.join("out")).unwrap(), 5);
+        assert_eq!(tree(&base.join("out")), tree(&base.join("in")));
+        // unchanged files are not written again
+        assert_eq!(sync_to_dir(&img, &base.join("out")).unwrap(), 0);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn fat16_round_trip() {
+        round_trip("fat16", &ImageOptions { size: 16 << 20, fat_type: FatType::Fat16, partition_table: false });
+    }
+
+    #[test]
+    fn fat32_round_trip() {
+        round_trip("fat32", &ImageOptions { size: 48 << 20, fat_type: FatType::Fat32, partition_table: true });
+    }
+
+    #[test]
+    fn short_name_collisions() {
+        let mut used = HashSet::new();
+        assert_eq!(short_name("readme.txt", &mut used), (*b"README  TXT", NTRES_BASE_LOWER | NTRES_EXT_LOWER, None));
+        assert_eq!(short_name("README.TXT", &mut used), (*b"README~1TXT", 0, Some("README.TXT".to_string())));
+        assert_eq!(short_name("Long Name One.txt", &mut used).0, *b"LONGNA~1TXT");
+        assert_eq!(short_name("Long Name Two.txt", &mut used).0, *b"LONGNA~2TXT");
+    }
+
+    #[test]
+    fn corrupted_chain_is_invalid_data() {
+        let base = temp_dir("corrupt");
+        make_tree(&base.join("in"));
+        let opts = ImageOptions { size: 16 << 20, fat_type: FatType::Fat16, partition_table: false };
+        let mut img = build_image(&base.join("in"), &opts).unwrap();
+
+        // point first cluster of multi-cluster file back to itself
+        let (fat_offset, cluster) = {
+            let volume = Volume::open(&img).unwrap();
+            let root = volume.root_dir().unwrap();
+            let entry = root.iter().find(|e| e.name == "Long File Name.data").unwrap();
+            (volume.fat_offset, entry.cluster)
+        };
+        let at = fat_offset + cluster as usize * 2;
+        img[at..at+2].copy_from_slice(&(cluster as u16).to_le_bytes());
+
+        let err = sync_to_dir(&img, &base.join("out")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+use std::fs::File;
+use std::io::{self, Write, BufWriter};
+use std::path::Path;
+
+use crate::support::crc::crc32;
+
+// RGB images written as binary PPM or PNG. PNG data is stored in uncompressed deflate blocks,
+// which keeps the encoder trivial; screenshots are small enough for this not to matter.
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum ImageFormat {
+    Png,
+    Ppm,
+}
+
+impl ImageFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Ppm => "ppm",
+        }
+    }
+}
+
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub rgb: Vec<u8>,
+}
+
+const DEFLATE_STORED_MAX: usize = 0xffff;
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Image {
+        Image { width, height, rgb: vec![0; width * height * 3] }
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, rgb: [u8; 3]) {
+        let offset = (y * self.width + x) * 3;
+        self.rgb[offset..offset+3].copy_from_slice(&rgb);
+    }
+
+    pub fn write(&self, path: &Path, format: ImageFormat) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        match format {
+            ImageFormat::Ppm => {
+                write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+                out.write_all(&self.rgb)?;
+            },
+            ImageFormat::Png => self.write_png(&mut out)?,
+        }
+        out.flush()
+    }
+
+    fn write_png(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+        let mut header = Vec::new();
+        header.extend((self.width as u32).to_be_bytes());
+        header.extend((self.height as u32).to_be_bytes());
+        header.extend([8, 2, 0, 0, 0]); // 8 bit depth, truecolor, deflate, adaptive filtering, no interlace
+        write_png_chunk(out, b"IHDR", &header)?;
+
+        // every scanline starts with filter type byte (0 - none)
+        let mut raw = Vec::with_capacity(self.height * (self.width * 3 + 1));
+        for row in self.rgb.chunks(self.width * 3) {
+            raw.push(0);
+            raw.extend(row);
+        }
+
+        let mut zlib = vec![0x78, 0x01]; // deflate, 32K window, no preset dictionary
+        let blocks = raw.chunks(DEFLATE_STORED_MAX).collect::<Vec<_>>();
+        for (i, block) in blocks.iter().enumerate() {
+            zlib.push(if i == blocks.len()-1 { 1 } else { 0 }); // BFINAL, BTYPE=00 (stored)
+            zlib.extend((block.len() as u16).to_le_bytes());
+            zlib.extend((!(block.len() as u16)).to_le_bytes());
+            zlib.extend(*block);
+        }
+        zlib.extend(adler32(&raw).to_be_bytes());
+        write_png_chunk(out, b"IDAT", &zlib)?;
+
+        write_png_chunk(out, b"IEND", &[])
+    }
+}
+
+fn write_png_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    let mut crc_data = kind.to_vec();
+    crc_data.extend(data);
+    out.write_all(&crc_data)?;
+    out.write_all(&crc32(&crc_data).to_be_bytes())
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1_u32, 0_u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
@@ -1 +1,13 @@
 pub mod tty;
+pub mod signal;
+pub mod crc;
+pub mod fat;
+pub mod monitor;
+pub mod image;
+pub mod time;
+pub mod sandbox;
+pub mod trace;
+pub mod diff;
+pub mod replay;
+pub mod events;
+pub mod text;
@@ -0,0 +1,19 @@
+use std::io::BufRead;
+use std::thread;
+
+use crate::support::events::{EventSender, HostEvent};
+
+// Commands typed on host stdin. Lines are read by background thread and queued as host events,
+// handled by simulation loop between instructions, so devices are only accessed from the simulation
+// thread.
+
+pub fn spawn_reader(events: EventSender) {
+    thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if !events.send(HostEvent::Monitor(line)) {
+                break;
+            }
+        }
+    });
+}
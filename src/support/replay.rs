@@ -0,0 +1,161 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::devices::gpio;
+
+// Record and replay of nondeterministic inputs. Devices pass every host input (UART RX bytes,
+// keyboard scan codes, GPIO input changes, monitor resets) and host time read through the log. Inputs are only
+// applied at instruction boundaries, so with the instruction count they fully determine the run.
+//
+// Log is a text file with `<instructions> <event>` lines:
+//   uart <hex bytes>, key <hex bytes>, gpio <pin> <0|1>, reset, rtc <unix seconds>, timer <microseconds>
+// Host clocks are sampled at most once per instruction and only changes are logged.
+
+pub struct InputLog {
+    mode: Mode,
+    instructions: u64,
+    clocks: [ClockSample; 2],
+}
+
+enum Mode {
+    Live,
+    Record(BufWriter<File>),
+    Replay { inputs: VecDeque<(u64, InputEvent)>, times: [VecDeque<(u64, i64)>; 2] },
+}
+
+#[derive(Clone)]
+pub enum InputEvent {
+    UartRx(Vec<u8>),
+    Keyboard(Vec<u8>),
+    Gpio(u8, bool),
+    Reset, // monitor reset command
+}
+
+#[derive(Clone, Copy)]
+pub enum HostClock {
+    Rtc = 0,   // unix seconds
+    Timer = 1, // microseconds
+}
+
+#[derive(Default)]
+struct ClockSample {
+    at: Option<u64>, // instruction count of last sample
+    value: i64,
+}
+
+impl InputLog {
+    pub fn live() -> InputLog {
+        InputLog { mode: Mode::Live, instructions: 0, clocks: Default::default() }
+    }
+
+    pub fn record(file: File) -> InputLog {
+        InputLog { mode: Mode::Record(BufWriter::new(file)), instructions: 0, clocks: Default::default() }
+    }
+
+    pub fn replay(path: &Path) -> io::Result<InputLog> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+
+        let mut inputs = VecDeque::new();
+        let mut times: [VecDeque<(u64, i64)>; 2] = Default::default();
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = || io::Error::new(io::ErrorKind::InvalidData, format!("input log line {}: invalid event `{}`", lineno+1, line));
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let at = fields[0].parse::<u64>().map_err(|_| error())?;
+            let bytes = || fields[2..].iter()
+                .map(|b| u8::from_str_radix(b, 16).ok())
+                .collect::<Option<Vec<u8>>>().ok_or_else(error);
+            match fields[1..] {
+                ["uart", ..] => inputs.push_back((at, InputEvent::UartRx(bytes()?))),
+                ["key", ..] => inputs.push_back((at, InputEvent::Keyboard(bytes()?))),
+                ["gpio", pin, level] => {
+                    let pin = gpio::parse_pin(pin).ok_or_else(error)?;
+                    let level = gpio::parse_level(level).ok_or_else(error)?;
+                    inputs.push_back((at, InputEvent::Gpio(pin, level)));
+                },
+                ["reset"] => inputs.push_back((at, InputEvent::Reset)),
+                ["rtc", value] => times[HostClock::Rtc as usize].push_back((at, value.parse().map_err(|_| error())?)),
+                ["timer", value] => times[HostClock::Timer as usize].push_back((at, value.parse().map_err(|_| error())?)),
+                _ => return Err(error()),
+            }
+        }
+        Ok(InputLog { mode: Mode::Replay { inputs, times }, instructions: 0, clocks: Default::default() })
+    }
+
+    pub fn is_replay(&self) -> bool {
+        matches!(self.mode, Mode::Replay { .. })
+    }
+
+    /// Called by simulation loop before each instruction
+    pub fn set_instructions(&mut self, instructions: u64) {
+        self.instructions = instructions;
+    }
+
+    /// Host input applied to device, logged when recording
+    pub fn input(&mut self, event: &InputEvent) {
+        let Mode::Record(file) = &mut self.mode else {
+            return;
+        };
+        let hex = |bytes: &[u8]| bytes.iter().map(|b| format!(" {:02x}", b)).collect::<String>();
+        let line = match event {
+            InputEvent::UartRx(bytes) => format!("{} uart{}", self.instructions, hex(bytes)),
+            InputEvent::Keyboard(bytes) => format!("{} key{}", self.instructions, hex(bytes)),
+            InputEvent::Gpio(pin, level) => format!("{} gpio {} {}", self.instructions, pin, *level as u8),
+            InputEvent::Reset => format!("{} reset", self.instructions),
+        };
+        writeln!(file, "{}", line).expect("Failed to write input log");
+    }
+
+    /// Recorded inputs to apply at current instruction
+    pub fn replayed_inputs(&mut self) -> Vec<InputEvent> {
+        let Mode::Replay { inputs, .. } = &mut self.mode else {
+            return Vec::new();
+        };
+        let mut due = Vec::new();
+        while let Some((_, event)) = inputs.front().filter(|e| e.0 <= self.instructions) {
+            due.push(event.clone());
+            inputs.pop_front();
+        }
+        due
+    }
+
+    /// Read host clock (with `read` when not replaying), value doesn't change within one instruction
+    pub fn host_time(&mut self, clock: HostClock, read: impl FnOnce() -> i64) -> i64 {
+        let sample = &mut self.clocks[clock as usize];
+        if sample.at == Some(self.instructions) {
+            return sample.value;
+        }
+        let value = match &mut self.mode {
+            Mode::Replay { times, .. } => {
+                let mut value = sample.value;
+                while let Some((_, v)) = times[clock as usize].front().filter(|t| t.0 <= self.instructions) {
+                    value = *v;
+                    times[clock as usize].pop_front();
+                }
+                value
+            },
+            _ => read(),
+        };
+        let changed = sample.at.is_none() || value != sample.value; // first sample is always logged
+        sample.value = value;
+        sample.at = Some(self.instructions);
+        if let (true, Mode::Record(file)) = (changed, &mut self.mode) {
+            let name = match clock { HostClock::Rtc => "rtc", HostClock::Timer => "timer" };
+            writeln!(file, "{} {} {}", self.instructions, name, sample.value).expect("Failed to write input log");
+        }
+        sample.value
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        match &mut self.mode {
+            Mode::Record(file) => file.flush(),
+            _ => Ok(()),
+        }
+    }
+}
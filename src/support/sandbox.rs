@@ -0,0 +1,53 @@
+use std::path::{Component, Path, PathBuf};
+
+// Host directory exposed to the guest. Guest paths are relative to the root, paths that would
+// leave it (absolute, `..`, symlinks pointing outside) are rejected.
+
+#[derive(Clone)]
+pub struct Sandbox {
+    root: PathBuf,
+}
+
+impl Sandbox {
+    pub fn new(root: &Path) -> std::io::Result<Sandbox> {
+        Ok(Sandbox { root: root.canonicalize()? })
+    }
+
+    /// Host path of guest path, None if it is outside of the sandbox
+    pub fn resolve(&self, name: &str) -> Option<PathBuf> {
+        let rel = Path::new(name);
+        if !rel.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+            return None;
+        }
+        let path = self.root.join(rel);
+
+        // file may not exist yet (created by write), then its parent directory has to be inside,
+        // dangling symlink would be followed by create, so it is rejected
+        let real = match path.canonicalize() {
+            Ok(real) => real,
+            Err(_) if path.symlink_metadata().is_ok() => return None,
+            Err(_) => path.parent()?.canonicalize().ok()?.join(path.file_name()?),
+        };
+        real.starts_with(&self.root).then_some(real)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn dangling_symlink_is_rejected() {
+        let base = std::env::temp_dir().join(format!("pcsn-sandbox-{}", std::process::id()));
+        let root = base.join("root");
+        fs::create_dir_all(&root).unwrap();
+        symlink(base.join("outside"), root.join("link")).unwrap();
+        let sandbox = Sandbox::new(&root).unwrap();
+
+        assert_eq!(sandbox.resolve("link"), None);
+        assert_eq!(sandbox.resolve("new.txt"), Some(root.canonicalize().unwrap().join("new.txt")));
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
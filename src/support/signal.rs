@@ -0,0 +1,20 @@
+use nix::sys::signal::{self, SigHandler, Signal};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Ctrl-C stops the simulation loop gracefully, so reports (profile, coverage) can be written on exit.
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_: nix::libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+pub fn install_interrupt_handler() {
+    unsafe { signal::signal(Signal::SIGINT, SigHandler::Handler(on_sigint)) }
+        .expect("Failed to install SIGINT handler");
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
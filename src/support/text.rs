@@ -0,0 +1,27 @@
+// Text arguments of script and monitor commands.
+
+/// Quoted string with backslash escapes, unquoted text is taken literally
+pub fn parse_text(s: &str) -> Result<String, String> {
+    let Some(inner) = s.strip_prefix('"') else {
+        return Ok(s.to_string());
+    };
+    let inner = inner.strip_suffix('"').ok_or_else(|| format!("unterminated string {}", s))?;
+    let mut text = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        text.push(match chars.next() {
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('e') => '\x1b',
+            Some('\\') => '\\',
+            Some('"') => '"',
+            other => return Err(format!("invalid escape `\\{}`", other.map(String::from).unwrap_or_default())),
+        });
+    }
+    Ok(text)
+}
@@ -0,0 +1,48 @@
+// Calendar conversions for unix time, in proleptic Gregorian calendar (UTC), and simulated time base.
+
+pub const SECS_PER_DAY: i64 = 86400;
+
+/// Simulated time advances with executed instructions, same nominal 25 MHz clock as framebuffer timing
+pub const INSTRUCTIONS_PER_SECOND: u64 = 25_000_000;
+
+/// Days since 1970-01-01 to (year, month, day)
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe/1460 + doe/36524 - doe/146096) / 365;
+    let doy = doe - (365*yoe + yoe/4 - yoe/100);
+    let mp = (5*doy + 2) / 153;
+    let day = (doy - (153*mp + 2)/5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// (year, month, day) to days since 1970-01-01
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153*mp + 2)/5 + day as i64 - 1;
+    let doe = yoe*365 + yoe/4 - yoe/100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Parse unix seconds or `YYYY-MM-DDTHH:MM:SS` (or with space instead of `T`) UTC date
+pub fn parse_time(s: &str) -> Option<i64> {
+    if let Ok(secs) = s.parse::<i64>() {
+        return Some(secs);
+    }
+    let (date, time) = s.split_once(['T', ' ']).unwrap_or((s, "00:00:00"));
+    let date: Vec<_> = date.split('-').map(|f| f.parse::<u32>().ok()).collect::<Option<_>>()?;
+    let time: Vec<_> = time.split(':').map(|f| f.parse::<u32>().ok()).collect::<Option<_>>()?;
+    let (&[year, month, day], &[hour, min, sec]) = (&date[..], &time[..]) else {
+        return None;
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || min > 59 || sec > 59 {
+        return None;
+    }
+    Some(days_from_civil(year as i64, month, day) * SECS_PER_DAY + (hour * 3600 + min * 60 + sec) as i64)
+}
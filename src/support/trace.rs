@@ -0,0 +1,24 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Execution trace (fetched instructions, registers, bus accesses) printed to stdout. Enabled by
+// default, turned off for headless and test runs where it only slows simulation down.
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// `println!` that is skipped (including formatting) when trace is disabled
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if $crate::support::trace::enabled() {
+            println!($($arg)*);
+        }
+    };
+}
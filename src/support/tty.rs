@@ -1,5 +1,5 @@
 use nix::fcntl::OFlag;
-use nix::pty::{grantpt, posix_openpt, ptsname, unlockpt};
+use nix::pty::{grantpt, posix_openpt, ptsname, unlockpt, PtyMaster};
 use nix::errno::Errno;
 use nix::unistd::dup;
 
@@ -7,47 +7,52 @@ use std::fs::File;
 use std::io::Read;
 use std::os::fd::{FromRawFd, AsRawFd};
 use std::thread;
-use std::sync::mpsc;
-use std::sync::mpsc::Receiver;
 use std::process::Command;
 
+use crate::support::events::{EventSender, HostEvent};
+
 pub struct Pty {
+    master: PtyMaster,
     pub master_write_file: File,
-    pub master_reciever: Receiver<u8>,
 
     pub slave_name: String,
 }
 
 impl Pty {
     pub fn open() -> Result<Pty, Errno> {
-        let mut master = posix_openpt(OFlag::O_RDWR)?;
+        let master = posix_openpt(OFlag::O_RDWR)?;
         grantpt(&master)?;
         unlockpt(&master)?;
         
         let slave_name = unsafe { ptsname(&master) }?;
         
-        let (tx, rx) = mpsc::sync_channel(8); // sync channel has a fixed size and blocks only sender
-        
         // UNIX HACKERY
         // Duping fd for new File to allow concurrent owning for writes from another thread,
-        // reads are converted with thread and accesible via Receiver channel.
+        // reads are done by reader thread on another dup (see spawn_reader).
         // Both Fds need to be closed (as_raw_fd, opposite to into_raw_fd, does not transfer
         // ownership (and preserve file closing)).
         let master_fd = master.as_raw_fd();
         let master_duped = unsafe { File::from_raw_fd( dup(master_fd)? ) };
         
-        // Convert blocking reads to non blocking channel 
+        Ok(Pty {master, slave_name, master_write_file: master_duped})
+    }
+
+    /// Convert blocking reads of typed input to host events, `event` wraps chunk of bytes
+    pub fn spawn_reader(&self, events: EventSender, event: fn(Vec<u8>) -> HostEvent) {
+        let fd = dup(self.master.as_raw_fd()).expect("Failed to duplicate PTY fd");
+        let mut reader = unsafe { File::from_raw_fd(fd) };
         thread::spawn(move || {
-            loop {
-                let mut buf = [0; 1];
-                master.read(&mut buf).unwrap();
-                tx.send(buf[0]).unwrap();
+            let mut buf = [0; 256];
+            // read fails with EIO when terminal window is closed
+            while let Ok(len @ 1..) = reader.read(&mut buf) {
+                if !events.send(event(buf[..len].to_vec())) {
+                    break;
+                }
             }
         });
-        
-        Ok(Pty {slave_name, master_write_file: master_duped, master_reciever: rx})
     }
 
+    #[allow(clippy::zombie_processes)] // terminal lives as long as the simulator
     pub fn spawn_term(&self) {
         Command::new("xterm")
             .arg("-bg")
@@ -0,0 +1,192 @@
+use std::cell::RefCell;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::{build_system, RunLimits, StopReason, SystemConfig};
+use crate::debug::symbols::SymbolTable;
+use crate::devices::disk::{DiskImage, Overlay};
+use crate::devices::framebuffer::FramebufferOutput;
+use crate::devices::rtc::RtcSource;
+use crate::devices::timer::TimerClock;
+use crate::devices::uart::{SerialHost, UartScript};
+use crate::support::diff::line_diff;
+use crate::support::image::ImageFormat;
+use crate::support::replay::InputLog;
+
+// Golden output test runner (`pcsn test <dir>`). Every subdirectory of <dir> containing prog.bin
+// is a test case, with optional files:
+//   data.bin      data memory image
+//   sd.img        SD card image, writes are kept in memory
+//   uart.in       bytes fed to UART RX
+//   uart.script   console automation script (see UartScript), its expect timeout fails the test
+//   expected.txt  expected UART output
+//   limit_ok      (empty) reaching the instruction limit is a pass, for guests that never stop
+// Tests run headless without trace, each system in its own thread. A test passes when UART output
+// matches expected.txt and the run ended with guest exit code 0 or halt (or instruction limit with
+// limit_ok). A panicking simulator fails only its own test.
+
+pub struct TestOptions {
+    pub max_instructions: u64,
+    pub timeout: Duration,
+    pub jobs: usize,
+    pub update: bool, // write actual output to expected.txt instead of failing
+}
+
+struct Outcome {
+    status: Status,
+    report: String,
+}
+
+#[derive(PartialEq)]
+enum Status {
+    Passed,
+    Failed,
+    Updated,
+}
+
+/// Run all tests in directory, returns true if none failed
+pub fn run_tests(dir: &Path, opts: &TestOptions) -> io::Result<bool> {
+    let mut cases: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<_>>()?;
+    cases.retain(|path| path.join("prog.bin").is_file());
+    cases.sort();
+    println!("running {} tests", cases.len());
+
+    let next = AtomicUsize::new(0);
+    let outcomes = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..opts.jobs.max(1) {
+            scope.spawn(|| loop {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                let Some(case) = cases.get(idx) else { break };
+                let outcome = match panic::catch_unwind(AssertUnwindSafe(|| run_case(case, opts))) {
+                    Ok(Ok(outcome)) => outcome,
+                    Ok(Err(e)) => Outcome { status: Status::Failed, report: format!("failed to run test: {}\n", e) },
+                    Err(payload) => Outcome { status: Status::Failed, report: format!("simulator panicked: {}\n", panic_message(&*payload)) },
+                };
+                let status = match outcome.status {
+                    Status::Passed => "ok",
+                    Status::Failed => "FAILED",
+                    Status::Updated => "updated",
+                };
+                println!("test {} ... {}", case_name(case), status);
+                outcomes.lock().unwrap().push((idx, outcome));
+            });
+        }
+    });
+
+    let mut outcomes = outcomes.into_inner().unwrap();
+    outcomes.sort_by_key(|(idx, _)| *idx);
+    let failed: Vec<_> = outcomes.iter().filter(|(_, o)| o.status == Status::Failed).collect();
+    for (idx, outcome) in &failed {
+        println!("\n---- {} ----\n{}", case_name(&cases[*idx]), outcome.report);
+    }
+    let updated = outcomes.iter().filter(|(_, o)| o.status == Status::Updated).count();
+    println!("\ntest result: {}. {} passed; {} failed; {} updated",
+             if failed.is_empty() { "ok" } else { "FAILED" }, outcomes.len() - failed.len() - updated, failed.len(), updated);
+    Ok(failed.is_empty())
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(msg), _) => msg,
+        (_, Some(msg)) => msg,
+        _ => "unknown panic",
+    }
+}
+
+fn case_name(case: &Path) -> String {
+    case.file_name().unwrap().to_string_lossy().into_owned()
+}
+
+fn read_optional(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// UART output kept in memory for comparison
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn run_case(case: &Path, opts: &TestOptions) -> io::Result<Outcome> {
+    let prog = fs::read(case.join("prog.bin"))?;
+    let data = read_optional(&case.join("data.bin"))?.unwrap_or_default();
+    let input = read_optional(&case.join("uart.in"))?.unwrap_or_default();
+    let script_path = case.join("uart.script");
+    let uart_script = if script_path.is_file() { Some(UartScript::load(&script_path)?) } else { None };
+    let expected_path = case.join("expected.txt");
+    let expected = read_optional(&expected_path)?;
+    let limit_ok = case.join("limit_ok").exists();
+    let sd_image = match File::open(case.join("sd.img")) {
+        Ok(file) => DiskImage::with_overlay(file, Overlay::in_memory())?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => DiskImage::memory(Rc::new(RefCell::new(Vec::new()))),
+        Err(e) => return Err(e),
+    };
+
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let config = SystemConfig {
+        serial: SerialHost::Headless { input: input.into(), output: Box::new(Capture(Rc::clone(&output))) },
+        uart_script,
+        sd_image, sd_fault: None, spi_flash: None, timer_clock: TimerClock::Instructions,
+        rtc_source: RtcSource::Deterministic { epoch: 0 }, // same output on every run
+        gpio_in: 0, gpio_script: None,
+        vga_output: FramebufferOutput { snapshot_prefix: None, format: ImageFormat::Png, every_frames: None, term: None },
+        kbd_term: None, kbd_script: None, monitor: false, host_queue: None, input_log: InputLog::live(), host_dir: None,
+        reset_keep_ram: false,
+        limits: RunLimits {
+            max_instructions: Some(opts.max_instructions), timeout: Some(opts.timeout), idle_pc: None, stop_on_halt: true,
+        },
+    };
+    let result = build_system(&prog, &data, config, Rc::new(SymbolTable::new()), vec![]);
+    let output = output.borrow();
+
+    let mut report = format!("{} after {} instructions\n", result.stop.describe(), result.instructions);
+    let stop_ok = match result.stop {
+        StopReason::GuestExit(0) | StopReason::Halted => true,
+        StopReason::MaxInstructions => limit_ok,
+        _ => false,
+    };
+    if stop_ok && expected.as_ref() == Some(&*output) {
+        return Ok(Outcome { status: Status::Passed, report });
+    }
+    if stop_ok && opts.update {
+        fs::write(&expected_path, &*output)?;
+        return Ok(Outcome { status: Status::Updated, report });
+    }
+
+    match &expected {
+        None => report.push_str("missing expected.txt (run with --update to create it)\n"),
+        Some(expected) if expected != &*output => {
+            let diff = line_diff(&String::from_utf8_lossy(expected), &String::from_utf8_lossy(&output));
+            if diff.is_empty() {
+                report.push_str("output differs in line endings or trailing newline\n");
+            } else {
+                report.push_str(&format!("output differs (- expected, + actual):\n{}", diff));
+            }
+        },
+        Some(_) => {},
+    }
+    report.push_str(&result.cpu_state);
+    Ok(Outcome { status: Status::Failed, report })
+}